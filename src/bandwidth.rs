@@ -0,0 +1,161 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use spin::Mutex as SpinMutex;
+
+/// Outbound bandwidth shared fairly by sessions, see `KcpConfig::bandwidth_pool`
+///
+/// Every session joining the pool is limited to its share of `rate` by weight, equal unless changed by
+/// `KcpStream::set_bandwidth_weight`. Shares are recomputed as sessions join and leave.
+pub struct BandwidthPool {
+    inner: SpinMutex<PoolInner>,
+}
+
+struct PoolInner {
+    /// Bytes per second shared by members
+    rate: u64,
+    /// Weight and current share of members, by id
+    members: HashMap<u64, (u32, Arc<AtomicU64>)>,
+    next_id: u64,
+}
+
+impl PoolInner {
+    fn recompute(&mut self) {
+        let total_weight = self.members.values().map(|&(weight, _)| weight as u64).sum::<u64>();
+        for (weight, share) in self.members.values() {
+            let rate = (self.rate as u128 * *weight as u128)
+                .checked_div(total_weight as u128)
+                .unwrap_or(0);
+            share.store(rate as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Debug for BandwidthPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_struct("BandwidthPool")
+            .field("rate", &inner.rate)
+            .field("members", &inner.members.len())
+            .finish()
+    }
+}
+
+impl BandwidthPool {
+    /// Pool of `rate` bytes per second
+    pub fn new(rate: u64) -> BandwidthPool {
+        BandwidthPool {
+            inner: SpinMutex::new(PoolInner {
+                rate,
+                members: HashMap::new(),
+                next_id: 0,
+            }),
+        }
+    }
+
+    /// Bytes per second shared by members
+    pub fn rate(&self) -> u64 {
+        self.inner.lock().rate
+    }
+
+    /// Change the bytes per second shared, applied to members right away
+    pub fn set_rate(&self, rate: u64) {
+        let mut inner = self.inner.lock();
+        inner.rate = rate;
+        inner.recompute();
+    }
+
+    /// Number of sessions sharing the pool
+    pub fn len(&self) -> usize {
+        self.inner.lock().members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Join with `weight`, the share is left when it is dropped
+    pub(crate) fn join(self: &Arc<Self>, weight: u32) -> BandwidthShare {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        let rate = Arc::new(AtomicU64::new(0));
+        inner.members.insert(id, (weight, rate.clone()));
+        inner.recompute();
+        BandwidthShare {
+            pool: self.clone(),
+            id,
+            rate,
+        }
+    }
+}
+
+/// Membership of a session in a `BandwidthPool`
+#[derive(Debug)]
+pub(crate) struct BandwidthShare {
+    pool: Arc<BandwidthPool>,
+    id: u64,
+    rate: Arc<AtomicU64>,
+}
+
+impl BandwidthShare {
+    /// Current share in bytes per second, updated by the pool
+    pub fn rate(&self) -> &Arc<AtomicU64> {
+        &self.rate
+    }
+
+    pub fn set_weight(&self, weight: u32) {
+        let mut inner = self.pool.inner.lock();
+        if let Some(member) = inner.members.get_mut(&self.id) {
+            member.0 = weight;
+        }
+        inner.recompute();
+    }
+}
+
+impl Drop for BandwidthShare {
+    fn drop(&mut self) {
+        let mut inner = self.pool.inner.lock();
+        inner.members.remove(&self.id);
+        inner.recompute();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{atomic::Ordering, Arc};
+
+    use super::BandwidthPool;
+
+    #[test]
+    fn fair_share() {
+        let pool = Arc::new(BandwidthPool::new(90_000));
+
+        let share1 = pool.join(1);
+        let share2 = pool.join(1);
+        let share3 = pool.join(1);
+        assert_eq!(pool.len(), 3);
+        for share in [&share1, &share2, &share3] {
+            assert_eq!(share.rate().load(Ordering::Relaxed), 30_000);
+        }
+
+        // Shares of the remaining sessions grow
+        drop(share3);
+        assert_eq!(share1.rate().load(Ordering::Relaxed), 45_000);
+        assert_eq!(share2.rate().load(Ordering::Relaxed), 45_000);
+
+        share1.set_weight(2);
+        assert_eq!(share1.rate().load(Ordering::Relaxed), 60_000);
+        assert_eq!(share2.rate().load(Ordering::Relaxed), 30_000);
+
+        pool.set_rate(30_000);
+        assert_eq!(share1.rate().load(Ordering::Relaxed), 20_000);
+        assert_eq!(share2.rate().load(Ordering::Relaxed), 10_000);
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+use spin::Mutex as SpinMutex;
+
+use crate::utils::now_millis;
+
+/// Source of time for a session's timers and KCP timestamps
+///
+/// Both must advance together, `now_millis` is the millisecond clock of the same time line as `now`.
+pub trait Clock: Debug + Send + Sync {
+    /// Current instant
+    fn now(&self) -> Instant;
+
+    /// Current time in milliseconds, may wrap around
+    fn now_millis(&self) -> u32;
+}
+
+/// The system clock, used by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn now_millis(&self) -> u32 {
+        now_millis()
+    }
+}
+
+/// Clock standing still until `advance` is called, for deterministic tests
+#[derive(Debug)]
+pub struct TestClock {
+    start: Instant,
+    start_millis: u32,
+    elapsed: SpinMutex<Duration>,
+}
+
+impl TestClock {
+    /// Clock starting at the current system time
+    pub fn new() -> TestClock {
+        TestClock {
+            start: Instant::now(),
+            start_millis: now_millis(),
+            elapsed: SpinMutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Move the clock forward by `duration`
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock() += duration;
+    }
+}
+
+impl Default for TestClock {
+    fn default() -> TestClock {
+        TestClock::new()
+    }
+}
+
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock()
+    }
+
+    fn now_millis(&self) -> u32 {
+        self.start_millis.wrapping_add(self.elapsed.lock().as_millis() as u32)
+    }
+}
@@ -1,6 +1,33 @@
-use std::{io::Write, time::Duration};
+use std::{
+    io::{self, ErrorKind, Write},
+    sync::Arc,
+    time::Duration,
+};
 
-use kcp::Kcp;
+use kcp::{Kcp, KcpResult};
+use log::LevelFilter;
+
+use crate::{
+    bandwidth::BandwidthPool,
+    clock::{Clock, SystemClock},
+    conv::ConvAllocator,
+    priority::SendQueueOrder,
+    transform::PacketTransform,
+};
+
+/// Largest MTU accepted, bounded by the maximum UDP payload size
+pub(crate) const MAX_MTU: usize = 65507;
+/// Smallest MTU accepted by KCP
+const MIN_MTU: usize = 50;
+
+/// Check `mtu` is within what KCP accepts and one UDP datagram holds
+pub(crate) fn check_mtu(mtu: usize) -> KcpResult<()> {
+    if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+        let err = io::Error::new(ErrorKind::InvalidInput, format!("invalid MTU {}", mtu));
+        return Err(err.into());
+    }
+    Ok(())
+}
 
 /// Kcp Delay Config
 #[derive(Debug, Clone, Copy)]
@@ -42,6 +69,12 @@ impl KcpNoDelayConfig {
         }
     }
 
+    /// Applies nodelay config onto `Kcp`
+    #[doc(hidden)]
+    pub fn apply_config<W: Write>(&self, k: &mut Kcp<W>) {
+        k.set_nodelay(self.nodelay, self.interval, self.resend, self.nc);
+    }
+
     /// Get a normal configuration
     ///
     /// 1. Disable NoDelay
@@ -58,14 +91,127 @@ impl KcpNoDelayConfig {
     }
 }
 
-/// Kcp Config
+/// NAT hole punching config
+///
+/// Empty datagrams sent by the client before its first KCP packet, to open NAT mappings on the way.
 #[derive(Debug, Clone, Copy)]
-pub struct KcpConfig {
+pub struct NatPunchConfig {
+    /// Number of datagrams sent
+    pub count: usize,
+    /// Interval between datagrams
+    pub interval: Duration,
+}
+
+impl Default for NatPunchConfig {
+    fn default() -> NatPunchConfig {
+        NatPunchConfig {
+            count: 3,
+            interval: Duration::from_millis(20),
+        }
+    }
+}
+
+/// What a listener does with datagrams of a conv without a session
+///
+/// A datagram starting a new session carries conv `0` (asking for one), one of the first data segments (a small
+/// `sn`, the first may be lost), or comes from a peer which hasn't received anything yet (`una` 0). Anything else
+/// from a peer without a session belongs to a session the listener doesn't know, e.g. one expired or lost in a
+/// restart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownConvPolicy {
+    /// Create a session for it, as if it started one
+    #[default]
+    Create,
+    /// Drop it silently
+    Ignore,
+    /// Drop it and reply with a FIN, ending the peer's session instead of leaving it to retransmit until it times out
+    Reset,
+}
+
+/// Upward MTU probing config
+///
+/// A probe is a padded window size segment of the size to be tried, the MTU is raised if the peer replies to it. Older
+/// versions don't reply to probes, the MTU stays as it is with them.
+/// Probes larger than the path MTU must be dropped rather than fragmented, which relies on the OS setting
+/// the DF bit, e.g. the default path MTU discovery of Linux.
+#[derive(Debug, Clone, Copy)]
+pub struct MtuProbeConfig {
+    /// Largest MTU probed
+    pub max_mtu: usize,
+    /// Interval between probes
+    pub interval: Duration,
+}
+
+impl Default for MtuProbeConfig {
+    fn default() -> MtuProbeConfig {
+        MtuProbeConfig {
+            max_mtu: 1472,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Weights of the components of `KcpStream::health_score`
+///
+/// Only relative values matter, they are normalized by their sum.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthScoreWeights {
+    /// Weight of retransmission ratio
+    pub loss: f32,
+    /// Weight of RTT trend
+    pub rtt: f32,
+    /// Weight of window stall ratio
+    pub stall: f32,
+}
+
+impl Default for HealthScoreWeights {
+    fn default() -> HealthScoreWeights {
+        HealthScoreWeights {
+            loss: 0.5,
+            rtt: 0.3,
+            stall: 0.2,
+        }
+    }
+}
+
+/// Parameters in effect on a live session, see `KcpStream::effective_config`
+#[derive(Debug, Clone, Copy)]
+pub struct EffectiveConfig {
     /// Max Transmission Unit
     pub mtu: usize,
+    /// Max Segment Size
+    pub mss: usize,
+    /// Send window size in segments
+    pub snd_wnd: u16,
+    /// Receive window size in segments
+    pub rcv_wnd: u16,
+    /// Internal update interval (ms)
+    pub interval: i32,
+    /// nodelay
+    pub nodelay: bool,
+    /// ACK number to enable fast resend
+    pub resend: u32,
+    /// Congestion control disabled
+    pub nc: bool,
+    /// Minimum RTO (ms)
+    pub min_rto: u32,
+}
+
+/// Kcp Config
+///
+/// Not `Copy` since 0.10, it owns the handles of `packet_transform`, `clock` and `bandwidth_pool`. Pass it by
+/// reference or `clone` it where it used to be copied, cloning only bumps the reference counts of the handles.
+#[derive(Debug, Clone)]
+pub struct KcpConfig {
+    /// Max Transmission Unit, at most 65507 bytes
+    pub mtu: usize,
     /// nodelay
     pub nodelay: KcpNoDelayConfig,
-    /// Send window size
+    /// Window size in segments, `(send window, receive window)`
+    ///
+    /// The two directions are independent, e.g. a large receive window and a small send window for
+    /// a link that is fast downstream and slow upstream. Other KCP parameters apply to both directions.
+    /// KCP doesn't take a receive window smaller than 128.
     pub wnd_size: (u16, u16),
     /// Session expire duration, default is 90 seconds
     pub session_expire: Duration,
@@ -74,7 +220,109 @@ pub struct KcpConfig {
     /// Flush ACKs immediately after input
     pub flush_acks_input: bool,
     /// Stream mode
+    ///
+    /// In stream mode KCP coalesces data into full segments for throughput, and `recv` returns whatever is
+    /// available, so any buffer size works. Otherwise every `send` is a message returned whole by one `recv`,
+    /// which needs a buffer large enough for it.
+    ///
+    /// Applied when a session is created, it can't be switched on a live session.
     pub stream: bool,
+    /// Retries of a UDP send failing transiently (`ENOBUFS`, `Interrupted`) before the packet is dropped, default is 3
+    ///
+    /// Other send failures are returned from the flush.
+    pub udp_send_retries: usize,
+    /// Maximum ACK segments accepted in one input datagram, as many as a datagram of `mtu` holds if `None`
+    pub max_input_acks: Option<usize>,
+    /// Level of per-packet logs emitted by sessions, still filtered by the global logger
+    ///
+    /// Set to `LevelFilter::Off` and raise it with `KcpStream::set_log_level` for the session being debugged.
+    pub log_level: LevelFilter,
+    /// Keep-alive interval, a session is expected to receive something from its peer at least once per interval
+    ///
+    /// A session that has sent nothing for an interval sends a keep-alive, which keeps quiet sessions
+    /// from being expired by the peer.
+    pub keepalive_interval: Option<Duration>,
+    /// Punch NAT before connecting
+    pub nat_punch: Option<NatPunchConfig>,
+    /// Conv allocator of listeners
+    pub conv_allocator: ConvAllocator,
+    /// Retried conv allocation requests from the same address within this window get the same conv,
+    /// default is 5 seconds. Zero disables it.
+    pub conv_alloc_dedup_window: Duration,
+    /// Convs of closed sessions won't be allocated again within this period, default is 30 seconds
+    pub conv_reuse_grace: Duration,
+    /// Disable congestion control regardless of `nodelay.nc`
+    ///
+    /// Only for dedicated links. On shared links KCP will keep sending at the full window size
+    /// while others back off, hurting everyone's traffic including its own.
+    pub no_congestion_control: bool,
+    /// Sessions whose estimated buffered bytes exceed it are closed, see `KcpStream::peak_memory`
+    pub max_session_memory: Option<usize>,
+    /// Flush before the next interval if sent data has been waiting longer than this
+    ///
+    /// Bounds the latency added by batching data per `nodelay.interval`.
+    pub max_buffer_latency: Option<Duration>,
+    /// Raise the fast resend threshold (`nodelay.resend`) above the reorder distance observed on received segments
+    ///
+    /// Avoids spurious fast retransmits on paths reordering heavily. Has no effect if fast resend is disabled.
+    pub adaptive_fast_resend: bool,
+    /// Most bytes of packets waiting to be sent after the UDP socket returned `WouldBlock`
+    ///
+    /// `send` waits while it is full, and KCP packets that don't fit are dropped for being retransmitted later.
+    /// Unbounded if `None`.
+    pub max_output_queue: Option<usize>,
+    /// Most datagrams sent per second regardless of their sizes, for networks policing by packet count
+    ///
+    /// Datagrams are spaced by `1 / max_pps`, the ones sent too early wait in the queue of packets delayed by
+    /// `WouldBlock`, bounded by `max_output_queue`.
+    pub max_pps: Option<u32>,
+    /// Most bytes of datagrams sent per second, capping the session's outbound bandwidth
+    ///
+    /// Datagrams are spaced by their sizes over the rate, the ones sent too early wait in the queue of packets
+    /// delayed by `WouldBlock`, bounded by `max_output_queue`. `None` or `0` sends right away.
+    pub send_rate_limit: Option<u64>,
+    /// Pool of outbound bandwidth shared with other sessions, each session is limited to its share of it
+    ///
+    /// Applies on top of `send_rate_limit`, the lower of the two limits the session.
+    pub bandwidth_pool: Option<Arc<BandwidthPool>>,
+    /// Spread the datagrams of a flush over the RTT instead of sending them back to back
+    ///
+    /// Datagrams are spaced at a window of segments per smoothed RTT, bursts lose less on constrained paths. Sent
+    /// right away until the first RTT sample. Off by default, it costs throughput on fast links.
+    pub pacing: bool,
+    /// Pace at the congestion window per smoothed RTT instead of the send window, like TCP pacing
+    ///
+    /// Implies `pacing`. KCP doesn't expose its congestion window, it is taken from the segments in flight while
+    /// more are waiting for the window, the send window is used until then. Without congestion control (`nc`), it is
+    /// the smaller of the send and remote windows.
+    pub cwnd_pacing: bool,
+    /// Probe for a larger MTU periodically, taking advantage of a path MTU increased mid-session
+    pub mtu_probe: Option<MtuProbeConfig>,
+    /// What listeners do with datagrams of unknown convs, default creates a session
+    pub unknown_conv_policy: UnknownConvPolicy,
+    /// Order of messages of a class waiting for the send window in `KcpStream::send_with_class`, default is FIFO
+    pub send_queue_order: SendQueueOrder,
+    /// Transformation of every datagram sent and received, datagrams are sent as they are if `None`
+    pub packet_transform: Option<Arc<dyn PacketTransform>>,
+    /// Close the session if packets waiting for a blocked UDP socket aren't sent within this period
+    ///
+    /// `send` fails with `TimedOut` afterwards.
+    pub write_timeout: Option<Duration>,
+    /// Close the session if nothing valid is received from the peer within this period after it is created
+    ///
+    /// Bounds how long a client waits for a dead server, separately from the idle timeout. The server is expected
+    /// to reply (at least acknowledge) the first message within it. `send` and `recv` fail with `TimedOut` afterwards.
+    pub connect_timeout: Option<Duration>,
+    /// Close the session once a segment has been sent this many times without being acknowledged
+    ///
+    /// Only counts transmissions of the same segment, a burst of loss recovering before it is reached doesn't close
+    /// the session. `send` and `recv` fail with `TimedOut` afterwards. If `None`, KCP's default of 20 only fails
+    /// `wait_acked`.
+    pub dead_link: Option<u32>,
+    /// Time source of sessions' timers, KCP timestamps and listeners' conv reuse, default is the system clock
+    ///
+    /// Replace it with a `TestClock` to control time in tests. Sessions still wait on tokio's timer between updates.
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Default for KcpConfig {
@@ -87,23 +335,55 @@ impl Default for KcpConfig {
             flush_write: false,
             flush_acks_input: false,
             stream: false,
+            udp_send_retries: 3,
+            max_input_acks: None,
+            log_level: LevelFilter::Trace,
+            keepalive_interval: None,
+            nat_punch: None,
+            conv_allocator: ConvAllocator::default(),
+            conv_alloc_dedup_window: Duration::from_secs(5),
+            conv_reuse_grace: Duration::from_secs(30),
+            no_congestion_control: false,
+            max_session_memory: None,
+            max_buffer_latency: None,
+            adaptive_fast_resend: false,
+            max_output_queue: None,
+            max_pps: None,
+            send_rate_limit: None,
+            bandwidth_pool: None,
+            pacing: false,
+            cwnd_pacing: false,
+            mtu_probe: None,
+            unknown_conv_policy: UnknownConvPolicy::Create,
+            send_queue_order: SendQueueOrder::Fifo,
+            packet_transform: None,
+            write_timeout: None,
+            connect_timeout: None,
+            dead_link: None,
+            clock: Arc::new(SystemClock),
         }
     }
 }
 
 impl KcpConfig {
-    /// Applies config onto `Kcp`
+    /// Applies config onto `Kcp`, fails if `mtu` is invalid
     #[doc(hidden)]
-    pub fn apply_config<W: Write>(&self, k: &mut Kcp<W>) {
-        k.set_mtu(self.mtu).expect("invalid MTU");
+    pub fn apply_config<W: Write>(&self, k: &mut Kcp<W>) -> KcpResult<()> {
+        check_mtu(self.mtu)?;
+        k.set_mtu(self.mtu)?;
 
-        k.set_nodelay(
-            self.nodelay.nodelay,
-            self.nodelay.interval,
-            self.nodelay.resend,
-            self.nodelay.nc,
-        );
+        let nodelay = KcpNoDelayConfig {
+            nc: self.nodelay.nc || self.no_congestion_control,
+            ..self.nodelay
+        };
+        nodelay.apply_config(k);
 
         k.set_wndsize(self.wnd_size.0, self.wnd_size.1);
+
+        if let Some(dead_link) = self.dead_link {
+            k.set_maximum_resend_times(dead_link);
+        }
+
+        Ok(())
     }
 }
@@ -0,0 +1,101 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+};
+
+/// Allocator of conv for clients connecting with conv 0
+#[derive(Debug, Clone, Copy, Default)]
+pub enum ConvAllocator {
+    /// Random conv for every allocation
+    #[default]
+    Random,
+    /// Random conv for every allocation, drawn from a custom source
+    ///
+    /// For a specific RNG required by the deployment, or a seeded one for reproducible tests.
+    RandomWith {
+        /// Random source, zeros are discarded
+        source: fn() -> u32,
+    },
+    /// Conv derived from the client's address and a server secret
+    ///
+    /// The same client always gets the same conv, so servers sharing the secret can route it without any
+    /// allocation state. A client reconnecting before its old session expires will be attached to that session,
+    /// and one reconnecting within `KcpConfig::conv_reuse_grace` after it closed gets a different conv.
+    Deterministic {
+        /// Server secret
+        secret: u64,
+    },
+}
+
+impl ConvAllocator {
+    /// Allocate a conv for `peer_addr`, never 0
+    ///
+    /// `attempt` is increased if the previous result is unusable, deterministic allocators derive another conv from it.
+    pub fn alloc(&self, peer_addr: SocketAddr, attempt: u32) -> u32 {
+        match *self {
+            ConvAllocator::Random => ConvAllocator::alloc_random(rand::random),
+            ConvAllocator::RandomWith { source } => ConvAllocator::alloc_random(source),
+            ConvAllocator::Deterministic { secret } => {
+                let mut hasher = DefaultHasher::new();
+                secret.hash(&mut hasher);
+                peer_addr.hash(&mut hasher);
+                if attempt > 0 {
+                    attempt.hash(&mut hasher);
+                }
+                match hasher.finish() as u32 {
+                    0 => 1,
+                    conv => conv,
+                }
+            }
+        }
+    }
+
+    fn alloc_random<F: FnMut() -> u32>(mut source: F) -> u32 {
+        loop {
+            let conv = source();
+            if conv != 0 {
+                break conv;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConvAllocator;
+    use std::{
+        net::SocketAddr,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    #[test]
+    fn deterministic_conv() {
+        let addr1 = "127.0.0.1:1000".parse::<SocketAddr>().unwrap();
+        let addr2 = "127.0.0.1:1001".parse::<SocketAddr>().unwrap();
+
+        let allocator = ConvAllocator::Deterministic { secret: 0xdeadbeef };
+        assert_eq!(allocator.alloc(addr1, 0), allocator.alloc(addr1, 0));
+        assert_ne!(allocator.alloc(addr1, 0), allocator.alloc(addr2, 0));
+        assert_ne!(allocator.alloc(addr1, 0), allocator.alloc(addr1, 1));
+
+        let other = ConvAllocator::Deterministic { secret: 0xbeefdead };
+        assert_ne!(allocator.alloc(addr1, 0), other.alloc(addr1, 0));
+    }
+
+    #[test]
+    fn random_with_source() {
+        static SEQ: AtomicU32 = AtomicU32::new(0);
+
+        fn source() -> u32 {
+            // 0, 7, 14, ...
+            SEQ.fetch_add(7, Ordering::Relaxed)
+        }
+
+        let addr = "127.0.0.1:1000".parse::<SocketAddr>().unwrap();
+        let allocator = ConvAllocator::RandomWith { source };
+        assert_eq!(allocator.alloc(addr, 0), 7);
+        assert_eq!(allocator.alloc(addr, 0), 14);
+        assert_eq!(allocator.alloc(addr, 1), 21);
+    }
+}
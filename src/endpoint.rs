@@ -0,0 +1,135 @@
+use std::{io, net::SocketAddr};
+
+use kcp::KcpResult;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+
+use crate::{config::KcpConfig, listener::KcpListener, stream::KcpStream};
+
+/// One `UdpSocket` shared by outgoing and incoming sessions
+///
+/// Datagrams are dispatched to sessions by the peer's address, a peer can either be connected or accepted.
+/// Connected sessions expire after `KcpConfig::session_expire` of inactivity, the same as accepted ones.
+#[derive(Debug)]
+pub struct KcpEndpoint {
+    config: KcpConfig,
+    listener: KcpListener,
+}
+
+impl KcpEndpoint {
+    /// Create an `KcpEndpoint` bound to `addr`
+    pub async fn bind<A: ToSocketAddrs>(config: KcpConfig, addr: A) -> KcpResult<KcpEndpoint> {
+        let udp = UdpSocket::bind(addr).await?;
+        KcpEndpoint::from_socket(config, udp).await
+    }
+
+    /// Create a `KcpEndpoint` from an existed `UdpSocket`
+    pub async fn from_socket(config: KcpConfig, udp: UdpSocket) -> KcpResult<KcpEndpoint> {
+        let listener = KcpListener::from_socket(config.clone(), udp).await?;
+        Ok(KcpEndpoint { config, listener })
+    }
+
+    /// Create a `KcpStream` connecting to `addr` from this endpoint
+    pub async fn connect(&self, addr: SocketAddr) -> KcpResult<KcpStream> {
+        self.listener.connect(&self.config, addr).await
+    }
+
+    /// Accept a new connected `KcpStream`
+    pub async fn accept(&mut self) -> KcpResult<(KcpStream, SocketAddr)> {
+        self.listener.accept().await
+    }
+
+    /// Get the local address of the underlying socket
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::KcpEndpoint;
+    use crate::{config::KcpConfig, listener::KcpListener, stream::KcpStream};
+    use std::time::Duration;
+    use tokio::{io::AsyncWriteExt, time};
+
+    #[tokio::test]
+    async fn connect_and_accept() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut endpoint = KcpEndpoint::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let endpoint_addr = endpoint.local_addr().unwrap();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        // Echo server behind the listener
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        // Inbound session to the endpoint
+        let client = tokio::spawn(async move {
+            let mut stream = KcpStream::connect(&config, endpoint_addr).await.unwrap();
+            stream.send(b"INBOUND").await.unwrap();
+            stream.flush().await.unwrap();
+            stream
+        });
+
+        // Outbound session from the same port
+        let mut outbound = endpoint.connect(listener_addr).await.unwrap();
+        outbound.send(b"OUTBOUND").await.unwrap();
+        outbound.flush().await.unwrap();
+
+        let (mut inbound, _) = endpoint.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = inbound.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"INBOUND");
+
+        let n = outbound.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"OUTBOUND");
+
+        let _client = client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn simultaneous_open() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let endpoint1 = KcpEndpoint::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let endpoint2 = KcpEndpoint::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+
+        // Both peers connect to each other, each with its own conv
+        let mut stream1 = endpoint1.connect(endpoint2.local_addr().unwrap()).await.unwrap();
+        let mut stream2 = endpoint2.connect(endpoint1.local_addr().unwrap()).await.unwrap();
+        let conv1 = stream1.session().conv().await;
+        let conv2 = stream2.session().conv().await;
+
+        stream1.send(b"HELLO 2").await.unwrap();
+        stream2.send(b"HELLO 1").await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let timeout = Duration::from_secs(5);
+        let n = time::timeout(timeout, stream1.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer[..n], b"HELLO 1");
+        let n = time::timeout(timeout, stream2.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer[..n], b"HELLO 2");
+
+        // The lower conv is kept by both
+        let conv = conv1.min(conv2);
+        assert_eq!(stream1.session().conv().await, conv);
+        assert_eq!(stream2.session().conv().await, conv);
+    }
+}
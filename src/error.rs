@@ -0,0 +1,44 @@
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io::{self, ErrorKind},
+};
+
+use kcp::Error as KcpError;
+
+/// KCP's send window is full, returned by sends not waiting for it to open
+///
+/// Carried by an `io::Error` of `ErrorKind::WouldBlock` for compatibility, tell it from other `WouldBlock`s
+/// with `SendWindowFull::is`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendWindowFull;
+
+impl SendWindowFull {
+    /// Check if `err` is caused by a full send window
+    pub fn is(err: &KcpError) -> bool {
+        match err {
+            KcpError::IoError(err) => err.get_ref().is_some_and(|err| err.is::<SendWindowFull>()),
+            _ => false,
+        }
+    }
+}
+
+impl Display for SendWindowFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("send window is full")
+    }
+}
+
+impl Error for SendWindowFull {}
+
+impl From<SendWindowFull> for io::Error {
+    fn from(err: SendWindowFull) -> io::Error {
+        io::Error::new(ErrorKind::WouldBlock, err)
+    }
+}
+
+impl From<SendWindowFull> for KcpError {
+    fn from(err: SendWindowFull) -> KcpError {
+        KcpError::IoError(err.into())
+    }
+}
@@ -1,14 +1,42 @@
 //! Library of KCP on Tokio
 
 pub use self::{
-    config::{KcpConfig, KcpNoDelayConfig},
-    listener::KcpListener,
-    stream::KcpStream,
+    bandwidth::BandwidthPool,
+    clock::{Clock, SystemClock, TestClock},
+    config::{
+        EffectiveConfig, HealthScoreWeights, KcpConfig, KcpNoDelayConfig, MtuProbeConfig, NatPunchConfig,
+        UnknownConvPolicy,
+    },
+    conv::ConvAllocator,
+    endpoint::KcpEndpoint,
+    error::SendWindowFull,
+    listener::{recv_peek_conv, KcpListener},
+    priority::{SendClass, SendQueueOrder},
+    rate::RateLimitSource,
+    stats::{CloseReason, KcpStats, PeerStats, SessionSummary},
+    stream::{KcpSender, KcpStream, SendLargeState},
+    transfer::kcp_send_stream,
+    transform::PacketTransform,
 };
 
+#[cfg(feature = "prometheus")]
+pub use self::prometheus::prometheus_metrics;
+
+mod bandwidth;
+mod clock;
 mod config;
+mod conv;
+mod endpoint;
+mod error;
 mod listener;
+mod priority;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod rate;
 mod session;
 mod skcp;
+mod stats;
 mod stream;
+mod transfer;
+mod transform;
 mod utils;
@@ -6,8 +6,10 @@ use std::{
 };
 
 use byte_string::ByteStr;
+use bytes::Bytes;
 use kcp::{Error as KcpError, KcpResult};
 use log::{debug, error, trace};
+use spin::Mutex as SpinMutex;
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
     sync::mpsc,
@@ -15,12 +17,22 @@ use tokio::{
     time,
 };
 
-use crate::{config::KcpConfig, session::KcpSessionManager, stream::KcpStream};
+use crate::{
+    config::{check_mtu, KcpConfig, UnknownConvPolicy},
+    session::{KcpSession, KcpSessionManager},
+    skcp::{self, KcpSocket},
+    stats::PeerStats,
+    stream::{self, KcpStream},
+    transform::decode_packet,
+};
 
 #[derive(Debug)]
 pub struct KcpListener {
     udp: Arc<UdpSocket>,
     accept_rx: mpsc::Receiver<(KcpStream, SocketAddr)>,
+    close_tx: mpsc::Sender<SocketAddr>,
+    register_tx: mpsc::Sender<(SocketAddr, Arc<KcpSession>)>,
+    peer_stats: Arc<SpinMutex<PeerStats>>,
     task_watcher: JoinHandle<()>,
 }
 
@@ -39,16 +51,25 @@ impl KcpListener {
 
     /// Create a `KcpListener` from an existed `UdpSocket`
     pub async fn from_socket(config: KcpConfig, udp: UdpSocket) -> KcpResult<KcpListener> {
+        // Sessions are created by the receive loop, which can't report an invalid config
+        check_mtu(config.mtu)?;
+
         let udp = Arc::new(udp);
         let server_udp = udp.clone();
 
         let (accept_tx, accept_rx) = mpsc::channel(1024 /* backlogs */);
+        let (close_tx, mut close_rx) = mpsc::channel(64);
+        let (register_tx, mut register_rx) = mpsc::channel::<(SocketAddr, Arc<KcpSession>)>(64);
+        let listener_close_tx = close_tx.clone();
+        let peer_stats = Arc::new(SpinMutex::new(PeerStats::default()));
+        let listener_peer_stats = peer_stats.clone();
         let task_watcher = tokio::spawn(async move {
-            let (close_tx, mut close_rx) = mpsc::channel(64);
-
-            let mut sessions = KcpSessionManager::new();
+            let mut sessions = KcpSessionManager::new(&config);
             let mut packet_buffer = [0u8; 65536];
             loop {
+                // Published before waiting, sessions only change while handling an event
+                *peer_stats.lock() = sessions.peer_stats();
+
                 tokio::select! {
                     peer_addr = close_rx.recv() => {
                         let peer_addr = peer_addr.expect("close_tx closed unexpectly");
@@ -56,6 +77,13 @@ impl KcpListener {
                         trace!("session peer_addr: {} removed", peer_addr);
                     }
 
+                    registered = register_rx.recv() => {
+                        if let Some((peer_addr, session)) = registered {
+                            sessions.insert(peer_addr, session);
+                            trace!("session peer_addr: {} registered", peer_addr);
+                        }
+                    }
+
                     recv_res = udp.recv_from(&mut packet_buffer) => {
                         match recv_res {
                             Err(err) => {
@@ -63,10 +91,16 @@ impl KcpListener {
                                 time::sleep(Duration::from_secs(1)).await;
                             }
                             Ok((n, peer_addr)) => {
+                                let n = decode_packet(config.packet_transform.as_deref(), &mut packet_buffer[..n]);
                                 let packet = &mut packet_buffer[..n];
 
                                 trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(packet));
 
+                                if packet.is_empty() {
+                                    // NAT punching datagrams
+                                    continue;
+                                }
+
                                 if packet.len() < kcp::KCP_OVERHEAD {
                                     error!("packet too short, received {} bytes, but at least {} bytes",
                                            packet.len(),
@@ -74,10 +108,48 @@ impl KcpListener {
                                     continue;
                                 }
 
+                                if skcp::is_fin(packet) && !sessions.contains_peer(&peer_addr) {
+                                    // Late FIN of a closed session, don't start a new one with it
+                                    trace!("ignored FIN from peer: {} without session", peer_addr);
+                                    continue;
+                                }
+
+                                if !sessions.contains_peer(&peer_addr) && !skcp::is_session_start(packet) {
+                                    let conv = kcp::get_conv(packet);
+                                    match sessions.unknown_conv_policy() {
+                                        UnknownConvPolicy::Create => {}
+                                        UnknownConvPolicy::Ignore => {
+                                            trace!("ignored unknown conv: {} from peer: {}", conv, peer_addr);
+                                            continue;
+                                        }
+                                        UnknownConvPolicy::Reset => {
+                                            trace!("reset unknown conv: {} from peer: {}", conv, peer_addr);
+                                            let mut packet = skcp::fin_packet(conv);
+                                            if let Some(ref transform) = config.packet_transform {
+                                                transform.encode(&mut packet);
+                                            }
+                                            let _ = udp.try_send_to(&packet, peer_addr);
+                                            continue;
+                                        }
+                                    }
+                                }
+
                                 let mut conv = kcp::get_conv(packet);
+                                // Both peers connecting to each other, the session migrates to the conv both keep
+                                // instead of being replaced by a new one
+                                if let Some(session_conv) = sessions.resolve_simultaneous_open(&peer_addr, packet) {
+                                    conv = session_conv;
+                                }
+
                                 if conv == 0 {
                                     // Allocate a conv for client.
-                                    conv = sessions.alloc_conv();
+                                    conv = match sessions.alloc_conv(peer_addr) {
+                                        Ok(conv) => conv,
+                                        Err(err) => {
+                                            error!("failed to allocate conv, error: {}, peer: {}", err, peer_addr);
+                                            continue;
+                                        }
+                                    };
                                     debug!("allocate {} conv for peer: {}", conv, peer_addr);
 
                                     kcp::set_conv(packet, conv);
@@ -133,10 +205,32 @@ impl KcpListener {
         Ok(KcpListener {
             udp: server_udp,
             accept_rx,
+            close_tx: listener_close_tx,
+            register_tx,
+            peer_stats: listener_peer_stats,
             task_watcher,
         })
     }
 
+    /// Create a `KcpStream` connecting to `addr` through the listening socket
+    ///
+    /// Datagrams from `addr` are dispatched to it instead of creating an accepted session.
+    pub(crate) async fn connect(&self, config: &KcpConfig, addr: SocketAddr) -> KcpResult<KcpStream> {
+        if let Some(ref nat_punch) = config.nat_punch {
+            stream::punch_nat(&self.udp, nat_punch, addr).await?;
+        }
+
+        let conv = rand::random();
+        let socket = KcpSocket::new(config, conv, self.udp.clone(), addr, config.stream)?;
+
+        let session = KcpSession::new_shared(socket, config.session_expire, Some((self.close_tx.clone(), addr)));
+        if self.register_tx.send((addr, session.clone())).await.is_err() {
+            return Err(KcpError::IoError(io::Error::other("listener closed unexpectedly")));
+        }
+
+        Ok(KcpStream::with_session(session))
+    }
+
     /// Accept a new connected `KcpStream`
     pub async fn accept(&mut self) -> KcpResult<(KcpStream, SocketAddr)> {
         match self.accept_rx.recv().await {
@@ -152,6 +246,34 @@ impl KcpListener {
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         self.udp.local_addr()
     }
+
+    /// Distinct peers seen and peers with live sessions, updated after every datagram and session change
+    pub fn peer_stats(&self) -> PeerStats {
+        *self.peer_stats.lock()
+    }
+}
+
+/// Receive one datagram from `udp` and extract its conv, without creating any session
+///
+/// Useful for inspecting a datagram before deciding whether to accept it.
+pub async fn recv_peek_conv(udp: &UdpSocket) -> io::Result<(u32, SocketAddr, Bytes)> {
+    // On the heap, the future of every caller would carry it otherwise
+    let mut packet_buffer = vec![0u8; 65536];
+    let (n, peer_addr) = udp.recv_from(&mut packet_buffer).await?;
+    let packet = &packet_buffer[..n];
+
+    if packet.len() < kcp::KCP_OVERHEAD {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "packet too short, received {} bytes, but at least {} bytes",
+                packet.len(),
+                kcp::KCP_OVERHEAD
+            ),
+        ));
+    }
+
+    Ok((kcp::get_conv(packet), peer_addr, Bytes::copy_from_slice(packet)))
 }
 
 #[cfg(unix)]
@@ -170,10 +292,59 @@ impl std::os::windows::io::AsRawSocket for KcpListener {
 
 #[cfg(test)]
 mod test {
-    use super::KcpListener;
-    use crate::{config::KcpConfig, stream::KcpStream};
+    use super::{recv_peek_conv, KcpListener};
+    use crate::{
+        config::{KcpConfig, KcpNoDelayConfig, NatPunchConfig, UnknownConvPolicy},
+        skcp,
+        stats::CloseReason,
+        stream::KcpStream,
+        transform::PacketTransform,
+    };
     use futures::future;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use kcp::Error as KcpError;
+    use std::{io::ErrorKind, sync::Arc, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+        sync::mpsc,
+        time,
+    };
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn send_between_threads() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            for _ in 0..2 {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                stream.send(&buffer[..n]).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        // Sessions are `Send`, used by tasks on any worker thread one after another
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        for message in [&b"HELLO"[..], &b"WORLD"[..]] {
+            stream = tokio::spawn(async move {
+                stream.send(message).await.unwrap();
+                let mut buffer = [0u8; 1024];
+                let n = stream.recv(&mut buffer).await.unwrap();
+                assert_eq!(&buffer[..n], message);
+                stream
+            })
+            .await
+            .unwrap();
+        }
+
+        server.await.unwrap();
+    }
 
     #[tokio::test]
     async fn multi_echo() {
@@ -181,7 +352,7 @@ mod test {
 
         let config = KcpConfig::default();
 
-        let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
         let server_addr = listener.local_addr().unwrap();
 
         tokio::spawn(async move {
@@ -206,6 +377,7 @@ mod test {
         let mut vfut = Vec::new();
 
         for _ in 0..100 {
+            let config = config.clone();
             vfut.push(async move {
                 let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
 
@@ -223,4 +395,600 @@ mod test {
 
         future::join_all(vfut).await;
     }
+
+    #[tokio::test]
+    async fn asymmetric_windows() {
+        let _ = env_logger::try_init();
+
+        const MESSAGES: usize = 200;
+
+        // Small send and large receive windows on the client, the other way around on the server (KCP's smallest
+        // receive window)
+        let client_config = KcpConfig {
+            wnd_size: (32, 256),
+            ..Default::default()
+        };
+        let server_config = KcpConfig {
+            wnd_size: (256, 128),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Both send everything before receiving, the server's receive window fills up and holds the client back
+        // until the server reads
+        async fn exchange(stream: &mut KcpStream, tag: u8) {
+            for i in 0..MESSAGES {
+                stream.send(&[tag, i as u8].repeat(500)).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+
+            let mut buffer = [0u8; 2048];
+            for i in 0..MESSAGES {
+                let n = time::timeout(Duration::from_secs(5), stream.recv(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buffer[..n], &[!tag, i as u8].repeat(500)[..]);
+            }
+        }
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let effective = stream.effective_config();
+            assert_eq!((effective.snd_wnd, effective.rcv_wnd), (256, 128));
+            exchange(&mut stream, !0x42).await;
+            // Keeps acknowledging until the client has everything
+            time::sleep(Duration::from_millis(500)).await;
+        });
+
+        let mut stream = KcpStream::connect(&client_config, server_addr).await.unwrap();
+        let effective = stream.effective_config();
+        assert_eq!((effective.snd_wnd, effective.rcv_wnd), (32, 256));
+        exchange(&mut stream, 0x42).await;
+
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn message_counter() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buffer = [0u8; 1024];
+            for _ in 0..5 {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                assert_eq!(b"MESSAGE", &buffer[..n]);
+            }
+
+            stream.messages_received()
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        for _ in 0..5 {
+            stream.send(b"MESSAGE").await.unwrap();
+            stream.flush().await.unwrap();
+        }
+
+        assert_eq!(server.await.unwrap(), 5);
+    }
+
+    #[tokio::test]
+    async fn send_after_close() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.session().close();
+
+        match stream.send(b"HELLO WORLD").await {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), ErrorKind::BrokenPipe),
+            r => panic!("send after close returns {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn logical_streams() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut received = Vec::new();
+            for _ in 0..4 {
+                received.push(stream.recv_with_stream().await.unwrap());
+            }
+            received
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send_on_stream(1, b"ONE-A").await.unwrap();
+        stream.send_on_stream(2, b"TWO-A").await.unwrap();
+        stream.send_on_stream(1, b"ONE-B").await.unwrap();
+        stream.send_on_stream(2, b"TWO-B").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let received = server.await.unwrap();
+        let stream_data = |id: u16| -> Vec<&[u8]> {
+            received
+                .iter()
+                .filter(|(i, _)| *i == id)
+                .map(|(_, data)| data.as_ref())
+                .collect()
+        };
+        assert_eq!(stream_data(1), vec![&b"ONE-A"[..], &b"ONE-B"[..]]);
+        assert_eq!(stream_data(2), vec![&b"TWO-A"[..], &b"TWO-B"[..]]);
+    }
+
+    #[tokio::test]
+    async fn send_large() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            wnd_size: (32, 32),
+            stream: true,
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| i as u8).collect();
+        let len = data.len();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut received = vec![0u8; len];
+            stream.read_exact(&mut received).await.unwrap();
+            received
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send_large(&data).await.unwrap();
+        stream.flush().await.unwrap();
+
+        assert!(server.await.unwrap() == data);
+    }
+
+    #[tokio::test]
+    async fn on_data() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let (data_tx, mut data_rx) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            stream.set_on_data(move |data| {
+                let _ = data_tx.send(data);
+            });
+            // Dropping the listener stops receiving for its sessions
+            (listener, stream)
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        stream.flush().await.unwrap();
+        let (_listener, _server_stream) = server.await.unwrap();
+
+        stream.send(b"WORLD").await.unwrap();
+        stream.flush().await.unwrap();
+
+        // The first message may have been received before the callback is set
+        let timeout = Duration::from_secs(5);
+        let mut data = time::timeout(timeout, data_rx.recv()).await.unwrap().unwrap();
+        if &data[..] == b"HELLO" {
+            data = time::timeout(timeout, data_rx.recv()).await.unwrap().unwrap();
+        }
+        assert_eq!(&data[..], b"WORLD");
+    }
+
+    #[tokio::test]
+    async fn send_from_on_data() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            stream.recv(&mut buffer).await.unwrap();
+
+            // Echo from the callback
+            let sender = stream.sender();
+            stream.set_on_data(move |data| {
+                sender.try_send(&data).unwrap();
+            });
+            // Dropping the listener stops receiving for its sessions
+            (listener, stream)
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"CONNECT").await.unwrap();
+        stream.flush().await.unwrap();
+        let (_listener, _server_stream) = time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+
+        stream.send(b"HELLO").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(5), stream.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer[..n], b"HELLO");
+    }
+
+    #[tokio::test]
+    async fn send_confirmed() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buffer = [0u8; 1024];
+            while let Ok(n) = stream.recv(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send_confirmed(b"HELLO WORLD").await.unwrap();
+        assert!(stream.session().kcp_socket().lock().can_close());
+
+        stream.send(b"HELLO").await.unwrap();
+        stream.send(b"WORLD").await.unwrap();
+        stream.commit().await.unwrap();
+        assert!(stream.session().kcp_socket().lock().can_close());
+    }
+
+    #[tokio::test]
+    async fn peek_conv() {
+        let _ = env_logger::try_init();
+
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut packet = [0u8; kcp::KCP_OVERHEAD];
+        kcp::set_conv(&mut packet, 0xdeadbeef);
+        client.send_to(&packet, server.local_addr().unwrap()).await.unwrap();
+
+        let (conv, peer_addr, data) = recv_peek_conv(&server).await.unwrap();
+        assert_eq!(conv, 0xdeadbeef);
+        assert_eq!(peer_addr, client.local_addr().unwrap());
+        assert_eq!(&data[..], &packet[..]);
+
+        client.send_to(b"SHORT", server.local_addr().unwrap()).await.unwrap();
+        let err = recv_peek_conv(&server).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn nat_punch() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nat_punch: Some(NatPunchConfig::default()),
+            ..Default::default()
+        };
+
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = KcpStream::connect(&config, server.local_addr().unwrap()).await.unwrap();
+        stream.send(b"HELLO WORLD").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        for _ in 0..NatPunchConfig::default().count {
+            let n = server.recv(&mut buffer).await.unwrap();
+            assert_eq!(n, 0);
+        }
+
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert!(n >= kcp::KCP_OVERHEAD);
+    }
+
+    #[tokio::test]
+    async fn close_summary() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let (summary_tx, mut summary_rx) = mpsc::unbounded_channel();
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..n], b"HELLO");
+
+            stream.set_on_close(move |summary| {
+                let _ = summary_tx.send(summary);
+            });
+            stream.send(b"WORLD!").await.unwrap();
+            stream.flush().await.unwrap();
+
+            // EOF after the client closed
+            let n = stream.recv(&mut buffer).await.unwrap();
+            assert_eq!(n, 0);
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let n = stream.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"WORLD!");
+        drop(stream);
+
+        let summary = time::timeout(Duration::from_secs(5), summary_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.reason, CloseReason::PeerClosed);
+        assert_eq!(summary.bytes_received, 5);
+        assert_eq!(summary.bytes_sent, 6);
+        assert!(summary.duration > Duration::ZERO);
+
+        server.await.unwrap();
+        // Exactly once
+        assert!(summary_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn packet_transform() {
+        let _ = env_logger::try_init();
+
+        /// Flip every bit
+        #[derive(Debug)]
+        struct NotTransform;
+
+        impl PacketTransform for NotTransform {
+            fn encode(&self, buf: &mut Vec<u8>) {
+                buf.iter_mut().for_each(|b| *b = !*b);
+            }
+
+            fn decode(&self, buf: &mut [u8]) -> usize {
+                buf.iter_mut().for_each(|b| *b = !*b);
+                buf.len()
+            }
+        }
+
+        let config = KcpConfig {
+            packet_transform: Some(Arc::new(NotTransform)),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+            // Keep the session until the client has received the echo
+            let _ = stream.recv(&mut buffer).await;
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        stream.flush().await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let n = stream.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"HELLO");
+    }
+
+    #[tokio::test]
+    async fn peer_stats() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut clients = Vec::new();
+        let mut servers = Vec::new();
+        for _ in 0..3 {
+            let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+            client.send(b"HELLO").await.unwrap();
+            client.flush().await.unwrap();
+
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            server.recv(&mut buffer).await.unwrap();
+
+            clients.push(client);
+            servers.push(server);
+        }
+
+        // Published once the listener is done with the datagram
+        async fn wait_active(listener: &KcpListener, active: usize) {
+            time::timeout(Duration::from_secs(5), async {
+                while listener.peer_stats().active != active {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .unwrap();
+        }
+
+        wait_active(&listener, 3).await;
+        assert_eq!(listener.peer_stats().total_seen, 3);
+
+        // Sessions of clients closed are evicted after their FIN
+        clients.truncate(1);
+        wait_active(&listener, 1).await;
+        assert_eq!(listener.peer_stats().total_seen, 3);
+    }
+
+    #[tokio::test]
+    async fn close_with_peer_gone() {
+        let _ = env_logger::try_init();
+
+        // Never acknowledges, dropped before the session is closed
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = KcpConfig {
+            session_expire: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut stream = KcpStream::connect(&config, server.local_addr().unwrap()).await.unwrap();
+        let (summary_tx, mut summary_rx) = mpsc::channel(1);
+        stream.set_on_close(move |summary| {
+            let _ = summary_tx.try_send(summary);
+        });
+        stream.send(b"HELLO").await.unwrap();
+        drop(server);
+
+        // The updater gives up waiting for the ACK
+        drop(stream);
+        let summary = time::timeout(Duration::from_secs(5), summary_rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(summary.reason, CloseReason::Closed);
+    }
+
+    #[tokio::test]
+    async fn connect_timeout() {
+        let _ = env_logger::try_init();
+
+        // Never replies
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = KcpConfig {
+            connect_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut stream = KcpStream::connect(&config, server.local_addr().unwrap()).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let result = time::timeout(Duration::from_secs(5), stream.recv(&mut buffer))
+            .await
+            .unwrap();
+        match result {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), ErrorKind::TimedOut),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    /// Data segment of a session the listener doesn't know
+    fn push_packet(conv: u32, sn: u32, una: u32) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&conv.to_le_bytes());
+        packet.extend_from_slice(&[81, 0, 128, 0]); // PUSH, frg, wnd
+        packet.extend_from_slice(&0u32.to_le_bytes()); // ts
+        packet.extend_from_slice(&sn.to_le_bytes()); // sn
+        packet.extend_from_slice(&una.to_le_bytes()); // una
+        packet.extend_from_slice(&5u32.to_le_bytes()); // len
+        packet.extend_from_slice(b"HELLO");
+        packet
+    }
+
+    #[tokio::test]
+    async fn unknown_conv_policy() {
+        let _ = env_logger::try_init();
+
+        for policy in [
+            UnknownConvPolicy::Create,
+            UnknownConvPolicy::Ignore,
+            UnknownConvPolicy::Reset,
+        ] {
+            let config = KcpConfig {
+                unknown_conv_policy: policy,
+                ..Default::default()
+            };
+
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            udp.send_to(&push_packet(0xdeadbeef, 1000, 1000), server_addr)
+                .await
+                .unwrap();
+
+            let accepted = time::timeout(Duration::from_millis(200), listener.accept()).await;
+            assert_eq!(accepted.is_ok(), policy == UnknownConvPolicy::Create, "{:?}", policy);
+
+            let mut packet = [0u8; 1024];
+            let reply = time::timeout(Duration::from_millis(200), udp.recv(&mut packet)).await;
+            match policy {
+                UnknownConvPolicy::Reset => {
+                    let n = reply.unwrap().unwrap();
+                    assert!(skcp::is_fin(&packet[..n]));
+                    assert_eq!(kcp::get_conv(&packet[..n]), 0xdeadbeef);
+                }
+                UnknownConvPolicy::Ignore => assert!(reply.is_err()),
+                UnknownConvPolicy::Create => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_conv_first_datagram_lost() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            unknown_conv_policy: UnknownConvPolicy::Reset,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // The datagram with sn 0 never arrived, the next one still starts the session
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.send_to(&push_packet(0xdeadbeef, 1, 0), server_addr).await.unwrap();
+
+        time::timeout(Duration::from_secs(1), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut packet = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(1), udp.recv(&mut packet))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!skcp::is_fin(&packet[..n]));
+    }
 }
@@ -0,0 +1,31 @@
+/// Priority class of messages sent by `KcpStream::send_with_class`
+///
+/// Messages waiting for the send window are handed to KCP by class, `Control` first, then `Interactive`,
+/// then `Bulk`. Messages of the same class keep their order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SendClass {
+    Control,
+    Interactive,
+    Bulk,
+}
+
+impl SendClass {
+    /// Number of classes
+    pub(crate) const COUNT: usize = 3;
+}
+
+/// Order in which messages of the same class waiting for the send window are handed to KCP
+///
+/// Only applies to messages queued by `KcpStream::send_with_class`, KCP still delivers what it was handed reliably
+/// and in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendQueueOrder {
+    /// Oldest first
+    #[default]
+    Fifo,
+    /// Newest first, for updates superseding each other, e.g. state synchronization
+    Lifo {
+        /// Drop the messages of a class still waiting when a newer one is queued
+        drop_superseded: bool,
+    },
+}
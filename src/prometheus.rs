@@ -0,0 +1,176 @@
+//! Prometheus text exposition of session metrics, enabled by the `prometheus` feature
+
+use std::{fmt::Write, net::SocketAddr};
+
+use crate::{skcp::KcpSocket, stream::KcpStream};
+
+/// Name, type and help of every metric, in the order of `Sample::values`
+const METRICS: [(&str, &str, &str); 10] = [
+    ("kcp_bytes_sent_total", "counter", "Bytes accepted by send"),
+    ("kcp_bytes_received_total", "counter", "Bytes returned by recv"),
+    (
+        "kcp_segments_sent_total",
+        "counter",
+        "Data segments sent, including retransmissions",
+    ),
+    (
+        "kcp_segments_received_total",
+        "counter",
+        "Data segments received, including duplicates",
+    ),
+    (
+        "kcp_segments_retransmitted_total",
+        "counter",
+        "Data segments retransmitted",
+    ),
+    (
+        "kcp_duplicate_segments_total",
+        "counter",
+        "Data segments received again after the first time",
+    ),
+    (
+        "kcp_srtt_seconds",
+        "gauge",
+        "Smoothed round trip time, 0 before the first ACK",
+    ),
+    (
+        "kcp_rto_seconds",
+        "gauge",
+        "Retransmission timeout approximated from the smoothed RTT",
+    ),
+    (
+        "kcp_wait_snd_segments",
+        "gauge",
+        "Segments waiting to be sent or acknowledged",
+    ),
+    ("kcp_memory_bytes", "gauge", "Estimated bytes buffered by the session"),
+];
+
+/// Metrics of one session
+pub(crate) struct Sample {
+    conv: u32,
+    peer: SocketAddr,
+    values: [f64; METRICS.len()],
+}
+
+impl Sample {
+    pub(crate) fn new(kcp: &KcpSocket) -> Sample {
+        let stats = kcp.stats();
+        Sample {
+            conv: kcp.conv(),
+            peer: kcp.peer_addr(),
+            values: [
+                kcp.bytes_sent() as f64,
+                kcp.bytes_received() as f64,
+                stats.segments_sent as f64,
+                stats.segments_received as f64,
+                stats.segments_retransmitted as f64,
+                kcp.duplicate_segments() as f64,
+                stats.srtt.unwrap_or_default().as_secs_f64(),
+                stats.rto.as_secs_f64(),
+                stats.wait_snd as f64,
+                kcp.memory_usage() as f64,
+            ],
+        }
+    }
+}
+
+/// Renders `samples` grouped by metric, so `HELP` and `TYPE` appear once per metric
+pub(crate) fn render(samples: &[Sample]) -> String {
+    let mut out = String::new();
+    for (idx, (name, kind, help)) in METRICS.iter().enumerate() {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        for sample in samples {
+            let _ = writeln!(
+                out,
+                "{}{{conv=\"{}\",peer=\"{}\"}} {}",
+                name, sample.conv, sample.peer, sample.values[idx]
+            );
+        }
+    }
+    out
+}
+
+/// Metrics of all `streams` in Prometheus text exposition format, labeled by conv and peer
+///
+/// Concatenating the `KcpStream::prometheus_metrics` of several streams repeats `HELP` and `TYPE` lines, which
+/// Prometheus rejects, so a `/metrics` endpoint serving many sessions should render them with this instead.
+pub fn prometheus_metrics<'a, I>(streams: I) -> String
+where
+    I: IntoIterator<Item = &'a KcpStream>,
+{
+    let samples = streams
+        .into_iter()
+        .map(|stream| Sample::new(&stream.session().kcp_socket().lock()))
+        .collect::<Vec<_>>();
+    render(&samples)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use tokio::net::UdpSocket;
+
+    use super::{render, Sample, METRICS};
+    use crate::{config::KcpConfig, skcp::KcpSocket};
+
+    /// Checks `text` against the Prometheus text format, returning the samples of every metric
+    fn parse(text: &str) -> HashMap<String, Vec<(String, f64)>> {
+        let mut types = HashSet::new();
+        let mut samples: HashMap<String, Vec<(String, f64)>> = HashMap::new();
+        for line in text.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                assert!(help.split_once(' ').is_some(), "malformed HELP: {}", line);
+            } else if let Some(ty) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = ty.split_once(' ').expect("malformed TYPE");
+                assert!(kind == "counter" || kind == "gauge", "unknown type: {}", line);
+                assert!(!samples.contains_key(name), "TYPE after samples: {}", line);
+                assert!(types.insert(name.to_owned()), "duplicated TYPE: {}", line);
+            } else {
+                let (name, rest) = line.split_once('{').expect("sample without labels");
+                let (labels, value) = rest.split_once("} ").expect("unterminated labels");
+                assert!(types.contains(name), "sample without TYPE: {}", line);
+                for label in labels.split(',') {
+                    let (key, value) = label.split_once('=').expect("malformed label");
+                    assert!(key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+                    assert!(value.len() >= 2 && value.starts_with('"') && value.ends_with('"'));
+                }
+                let value = value.parse::<f64>().expect("malformed value");
+                samples
+                    .entry(name.to_owned())
+                    .or_default()
+                    .push((labels.to_owned(), value));
+            }
+        }
+        samples
+    }
+
+    #[tokio::test]
+    async fn prometheus_metrics() {
+        let _ = env_logger::try_init();
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp1 = KcpSocket::new(&config, 1, udp.clone(), target_addr, false).unwrap();
+        let kcp2 = KcpSocket::new(&config, 2, udp, target_addr, false).unwrap();
+        kcp1.send(b"HELLO").await.unwrap();
+
+        let samples = parse(&kcp1.prometheus_metrics());
+        assert_eq!(samples.len(), METRICS.len());
+        let labels = format!("conv=\"1\",peer=\"{}\"", target_addr);
+        assert_eq!(samples["kcp_bytes_sent_total"], vec![(labels.clone(), 5.0)]);
+        assert_eq!(samples["kcp_wait_snd_segments"], vec![(labels, 1.0)]);
+
+        let samples = parse(&render(&[Sample::new(&kcp1), Sample::new(&kcp2)]));
+        for (name, ..) in METRICS.iter() {
+            assert_eq!(samples[*name].len(), 2, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,16 @@
+/// Mechanism limiting the send rate, see `KcpStream::current_send_rate_limit`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitSource {
+    /// Local send window, `KcpConfig::wnd_size.0`
+    SendWindow,
+    /// Receive window advertised by the peer
+    RemoteWindow,
+    /// `KcpConfig::max_pps` datagrams of the MTU per second
+    MaxPps,
+    /// `KcpConfig::send_rate_limit`
+    SendRateLimit,
+    /// Share of `KcpConfig::bandwidth_pool`
+    BandwidthPool,
+    /// `KcpConfig::pacing`, a window per smoothed RTT spread over it
+    Pacing,
+}
@@ -1,6 +1,7 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
     fmt::{self, Debug},
+    io::{self, ErrorKind},
     net::SocketAddr,
     ops::Deref,
     sync::{
@@ -11,8 +12,9 @@ use std::{
 };
 
 use byte_string::ByteStr;
+use bytes::Bytes;
 use kcp::KcpResult;
-use log::{error, trace};
+use log::{debug, error, trace};
 use spin::Mutex as SpinMutex;
 use tokio::{
     net::UdpSocket,
@@ -20,7 +22,27 @@ use tokio::{
     time::{self, Instant},
 };
 
-use crate::{skcp::KcpSocket, KcpConfig};
+use crate::{
+    clock::Clock,
+    config::UnknownConvPolicy,
+    conv::ConvAllocator,
+    skcp::KcpSocket,
+    stats::{CloseReason, PeerStats, SessionSummary},
+    transform::decode_packet,
+    KcpConfig,
+};
+
+/// Callback receiving messages pushed by a session
+pub type OnDataCallback = Box<dyn FnMut(Bytes) + Send>;
+
+/// Callback receiving the summary of a closed session
+pub type OnCloseCallback = Box<dyn FnOnce(SessionSummary) + Send>;
+
+/// Most datagrams a client session inputs per wakeup
+const CLIENT_RECV_BATCH: usize = 64;
+
+/// Convs tried by `alloc_conv` before giving up, an allocator repeating used convs won't find a free one
+const MAX_CONV_ALLOC_ATTEMPTS: u32 = 64;
 
 pub struct KcpSession {
     socket: SpinMutex<KcpSocket>,
@@ -29,6 +51,8 @@ pub struct KcpSession {
     session_close_notifier: Option<(mpsc::Sender<SocketAddr>, SocketAddr)>,
     input_tx: mpsc::Sender<Vec<u8>>,
     notifier: Notify,
+    on_data: SpinMutex<Option<OnDataCallback>>,
+    on_close: SpinMutex<Option<OnCloseCallback>>,
 }
 
 impl Drop for KcpSession {
@@ -50,6 +74,8 @@ impl Debug for KcpSession {
             .field("session_close_notifier", &self.session_close_notifier)
             .field("input_tx", &self.input_tx)
             .field("notifier", &self.notifier)
+            .field("on_data", &self.on_data.lock().is_some())
+            .field("on_close", &self.on_close.lock().is_some())
             .finish()
     }
 }
@@ -68,6 +94,8 @@ impl KcpSession {
             session_close_notifier,
             input_tx,
             notifier: Notify::new(),
+            on_data: SpinMutex::new(None),
+            on_close: SpinMutex::new(None),
         }
     }
 
@@ -81,6 +109,7 @@ impl KcpSession {
         let (input_tx, mut input_rx) = mpsc::channel(64);
 
         let udp_socket = socket.udp_socket().clone();
+        let packet_transform = socket.packet_transform().cloned();
 
         let session = Arc::new(KcpSession::new(
             socket,
@@ -104,37 +133,26 @@ impl KcpSession {
                                     error!("[SESSION] UDP recv failed, error: {}", err);
                                 }
                                 Ok(n) => {
-                                    let input_buffer = &input_buffer[..n];
-
-                                    if input_buffer.len() < kcp::KCP_OVERHEAD {
-                                        error!("packet too short, received {} bytes, but at least {} bytes",
-                                               input_buffer.len(),
-                                               kcp::KCP_OVERHEAD);
-                                        continue;
-                                    }
-
-                                    let input_conv = kcp::get_conv(input_buffer);
-                                    trace!("[SESSION] UDP recv {} bytes, conv: {}, going to input {:?}",
-                                           n, input_conv, ByteStr::new(input_buffer));
-
-                                    let mut socket = session.socket.lock();
-
-                                    // Server may allocate another conv for this client.
-                                    if !socket.waiting_conv() && socket.conv() != input_conv {
-                                        trace!("[SESSION] UDP input conv: {} replaces session conv: {}", input_conv, socket.conv());
-                                        socket.set_conv(input_conv);
-                                    }
-
-                                    match socket.input(input_buffer) {
-                                        Ok(true) => {
-                                            trace!("[SESSION] UDP input {} bytes and waked sender/receiver", n);
-                                        }
-                                        Ok(false) => {}
-                                        Err(err) => {
-                                            error!("[SESSION] UDP input {} bytes error: {}, input buffer {:?}",
-                                                   n, err, ByteStr::new(input_buffer));
+                                    let n = decode_packet(packet_transform.as_deref(), &mut input_buffer[..n]);
+                                    session.input_client_packet(&input_buffer[..n]);
+
+                                    // Drain datagrams already queued without going back to the reactor,
+                                    // capped for not starving other tasks on a flooded socket
+                                    for _ in 1..CLIENT_RECV_BATCH {
+                                        match udp_socket.try_recv(&mut input_buffer) {
+                                            Ok(n) => {
+                                                let n = decode_packet(packet_transform.as_deref(), &mut input_buffer[..n]);
+                                                session.input_client_packet(&input_buffer[..n]);
+                                            }
+                                            Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                                            Err(err) => {
+                                                error!("[SESSION] UDP recv failed, error: {}", err);
+                                                break;
+                                            }
                                         }
                                     }
+
+                                    session.dispatch_on_data();
                                 }
                             }
                         }
@@ -142,8 +160,8 @@ impl KcpSession {
                         // bytes received from listener socket
                         input_opt = input_rx.recv() => {
                             if let Some(input_buffer) = input_opt {
-                                let mut socket = session.socket.lock();
-                                match socket.input(&input_buffer) {
+                                let input_result = session.socket.lock().input(&input_buffer);
+                                match input_result {
                                     Ok(waked) => {
                                         // trace!("[SESSION] UDP input {} bytes from channel {:?}",
                                         //        input_buffer.len(), ByteStr::new(&input_buffer));
@@ -155,6 +173,8 @@ impl KcpSession {
                                                input_buffer.len(), err, ByteStr::new(&input_buffer));
                                     }
                                 }
+
+                                session.dispatch_on_data();
                             }
                         }
                     }
@@ -166,21 +186,75 @@ impl KcpSession {
         {
             let session = session.clone();
             tokio::spawn(async move {
-                while !session.closed.load(Ordering::Relaxed) {
+                let mut reason = CloseReason::Closed;
+                // Since when the session lingers after `close`
+                let mut closing_since = None;
+                // Keeps updating after `close` until everything sent is acknowledged, the FIN follows that
+                loop {
                     let next = {
                         let mut socket = session.socket.lock();
 
                         let is_closed = session.closed.load(Ordering::Acquire);
-                        if is_closed && socket.can_close() {
-                            trace!("[SESSION] KCP session closing");
+                        if is_closed {
+                            if socket.can_close() {
+                                trace!("[SESSION] KCP session closing");
+                                break;
+                            }
+
+                            // The peer may be gone, don't wait for its ACKs longer than it would be expired
+                            let since = *closing_since.get_or_insert_with(Instant::now);
+                            if socket.is_dead_link() || since.elapsed() > session.session_expire {
+                                trace!(
+                                    "[SESSION] KCP session closing with data unacknowledged, conv: {}",
+                                    socket.conv()
+                                );
+                                break;
+                            }
+                        }
+
+                        if socket.is_shut_down() {
+                            trace!("[SESSION] KCP session shut down by both peers, conv: {}", socket.conv());
+                            break;
+                        }
+
+                        if socket.is_peer_closed() {
+                            trace!("[SESSION] KCP session closed by peer, conv: {}", socket.conv());
+                            reason = CloseReason::PeerClosed;
+                            break;
+                        }
+
+                        if socket.is_memory_exceeded() {
+                            error!(
+                                "[SESSION] force close session exceeded memory limit, conv: {}, peak_memory: {} bytes",
+                                socket.conv(),
+                                socket.peak_memory()
+                            );
+                            reason = CloseReason::MemoryExceeded;
+                            break;
+                        }
+
+                        if socket.is_connect_timed_out() {
+                            error!("[SESSION] close session timed out connecting, conv: {}", socket.conv());
+                            reason = CloseReason::ConnectTimeout;
+                            break;
+                        }
+
+                        if socket.is_dead() {
+                            error!("[SESSION] close session with dead link, conv: {}", socket.conv());
+                            reason = CloseReason::DeadLink;
+                            break;
+                        }
+
+                        if socket.is_write_timed_out() {
+                            error!("[SESSION] close session timed out writing, conv: {}", socket.conv());
+                            reason = CloseReason::WriteTimeout;
                             break;
                         }
 
                         // server socket expires
                         if !is_client {
                             // If this is a server stream, close it automatically after a period of time
-                            let last_update_time = socket.last_update_time();
-                            let elapsed = last_update_time.elapsed();
+                            let elapsed = socket.idle_time();
 
                             if elapsed > session.session_expire {
                                 if elapsed > session.session_expire * 2 {
@@ -190,6 +264,7 @@ impl KcpSession {
                                         socket.conv(),
                                         elapsed.as_secs()
                                     );
+                                    reason = CloseReason::Inactive;
                                     break;
                                 }
 
@@ -199,6 +274,7 @@ impl KcpSession {
                                         socket.conv(),
                                         elapsed.as_secs()
                                     );
+                                    reason = CloseReason::Inactive;
                                     session.closed.store(true, Ordering::Release);
                                 }
                             }
@@ -210,7 +286,8 @@ impl KcpSession {
                         }
 
                         match socket.update() {
-                            Ok(next_next) => Instant::from_std(next_next),
+                            // `next_next` is by the session's clock, wait the same period on tokio's timer
+                            Ok(next_next) => Instant::now() + next_next.saturating_duration_since(socket.now()),
                             Err(err) => {
                                 error!("[SESSION] KCP update failed, error: {}", err);
                                 Instant::now() + Duration::from_millis(10)
@@ -224,12 +301,22 @@ impl KcpSession {
                     }
                 }
 
-                {
+                let summary = {
                     // Close the socket.
                     // Wake all pending tasks and let all send/recv return EOF
 
                     let mut socket = session.socket.lock();
+                    // Closed locally after reading everything up to the peer's FIN
+                    if reason == CloseReason::Closed && socket.is_peer_closed() {
+                        reason = CloseReason::PeerClosed;
+                    }
                     socket.close();
+                    socket.summary(reason)
+                };
+
+                debug!("[SESSION] KCP session closed, {:?}", summary);
+                if let Some(on_close) = session.on_close.lock().take() {
+                    on_close(summary);
                 }
 
                 if let Some((ref notifier, peer_addr)) = session.session_close_notifier {
@@ -246,6 +333,75 @@ impl KcpSession {
         session
     }
 
+    /// Push messages received to `on_data` if it is set
+    ///
+    /// Called without holding the socket's lock.
+    fn dispatch_on_data(&self) {
+        let mut on_data = self.on_data.lock();
+        if let Some(ref mut on_data) = *on_data {
+            let messages = self.socket.lock().recv_ready_messages();
+            for message in messages {
+                on_data(message);
+            }
+        }
+    }
+
+    pub fn set_on_data(&self, on_data: Option<OnDataCallback>) {
+        *self.on_data.lock() = on_data;
+    }
+
+    pub fn set_on_close(&self, on_close: Option<OnCloseCallback>) {
+        *self.on_close.lock() = on_close;
+    }
+
+    /// Input a datagram received by a client session from its own socket
+    fn input_client_packet(&self, input_buffer: &[u8]) {
+        let n = input_buffer.len();
+        if n < kcp::KCP_OVERHEAD {
+            error!(
+                "packet too short, received {} bytes, but at least {} bytes",
+                n,
+                kcp::KCP_OVERHEAD
+            );
+            return;
+        }
+
+        let input_conv = kcp::get_conv(input_buffer);
+        trace!(
+            "[SESSION] UDP recv {} bytes, conv: {}, going to input {:?}",
+            n,
+            input_conv,
+            ByteStr::new(input_buffer)
+        );
+
+        let mut socket = self.socket.lock();
+
+        // Server may allocate another conv for this client, unless the peer is connecting to us at the same time.
+        if !socket.resolve_simultaneous_open(input_buffer) && !socket.waiting_conv() && socket.conv() != input_conv {
+            trace!(
+                "[SESSION] UDP input conv: {} replaces session conv: {}",
+                input_conv,
+                socket.conv()
+            );
+            socket.set_conv(input_conv);
+        }
+
+        match socket.input(input_buffer) {
+            Ok(true) => {
+                trace!("[SESSION] UDP input {} bytes and waked sender/receiver", n);
+            }
+            Ok(false) => {}
+            Err(err) => {
+                error!(
+                    "[SESSION] UDP input {} bytes error: {}, input buffer {:?}",
+                    n,
+                    err,
+                    ByteStr::new(input_buffer)
+                );
+            }
+        }
+    }
+
     pub fn kcp_socket(&self) -> &SpinMutex<KcpSocket> {
         &self.socket
     }
@@ -255,6 +411,10 @@ impl KcpSession {
         self.notify();
     }
 
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
     pub async fn input(&self, buf: &[u8]) -> Result<(), SessionClosedError> {
         self.input_tx.send(buf.to_owned()).await.map_err(|_| SessionClosedError)
     }
@@ -289,22 +449,172 @@ impl Deref for KcpSessionUniq {
 
 pub struct KcpSessionManager {
     sessions: HashMap<SocketAddr, KcpSessionUniq>,
+    conv_allocator: ConvAllocator,
+    conv_alloc_dedup_window: Duration,
+    recent_allocs: HashMap<SocketAddr, (u32, std::time::Instant)>,
+    conv_reuse_grace: Duration,
+    quarantined_convs: HashMap<u32, std::time::Instant>,
+    /// Convs of live sessions, with the number of sessions using it
+    active_convs: HashMap<u32, usize>,
+    unknown_conv_policy: UnknownConvPolicy,
+    /// Every peer address that had a session
+    seen_peers: HashSet<SocketAddr>,
+    /// Time source of the dedup window and the reuse grace
+    clock: Arc<dyn Clock>,
 }
 
 impl KcpSessionManager {
-    pub fn new() -> KcpSessionManager {
+    pub fn new(config: &KcpConfig) -> KcpSessionManager {
         KcpSessionManager {
             sessions: HashMap::new(),
+            conv_allocator: config.conv_allocator,
+            conv_alloc_dedup_window: config.conv_alloc_dedup_window,
+            recent_allocs: HashMap::new(),
+            conv_reuse_grace: config.conv_reuse_grace,
+            quarantined_convs: HashMap::new(),
+            active_convs: HashMap::new(),
+            unknown_conv_policy: config.unknown_conv_policy,
+            seen_peers: HashSet::new(),
+            clock: config.clock.clone(),
+        }
+    }
+
+    /// Allocate a conv for `peer_addr`, unique among live sessions
+    ///
+    /// Requests retried by the same peer within `conv_alloc_dedup_window` get the same conv.
+    ///
+    /// The conv is reserved only after its session is created. A conv allocated twice before either session is
+    /// created still collides, which doesn't happen as long as the caller creates the session right away.
+    ///
+    /// Fails with `AddrInUse` if `MAX_CONV_ALLOC_ATTEMPTS` convs in a row are in use or quarantined.
+    pub fn alloc_conv(&mut self, peer_addr: SocketAddr) -> KcpResult<u32> {
+        let now = self.clock.now();
+        let window = self.conv_alloc_dedup_window;
+        self.recent_allocs
+            .retain(|_, &mut (_, alloc_time)| now.duration_since(alloc_time) < window);
+
+        if let Some(&(conv, _)) = self.recent_allocs.get(&peer_addr) {
+            trace!("reused conv: {} for retried allocation from peer: {}", conv, peer_addr);
+            return Ok(conv);
+        }
+
+        let grace = self.conv_reuse_grace;
+        self.quarantined_convs
+            .retain(|_, &mut closed_time| now.duration_since(closed_time) < grace);
+
+        let mut attempt = 0;
+        let conv = loop {
+            if attempt >= MAX_CONV_ALLOC_ATTEMPTS {
+                let err = io::Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("no free conv after {} attempts", MAX_CONV_ALLOC_ATTEMPTS),
+                );
+                return Err(err.into());
+            }
+            let conv = self.conv_allocator.alloc(peer_addr, attempt);
+            if self.active_convs.contains_key(&conv) {
+                trace!("conv: {} is in use, skipped for peer: {}", conv, peer_addr);
+            } else if self.quarantined_convs.contains_key(&conv) {
+                trace!("conv: {} is closed recently, skipped for peer: {}", conv, peer_addr);
+            } else {
+                break conv;
+            }
+            attempt += 1;
+        };
+
+        if !window.is_zero() {
+            self.recent_allocs.insert(peer_addr, (conv, now));
         }
+        Ok(conv)
     }
 
-    #[inline]
-    pub fn alloc_conv(&mut self) -> u32 {
-        rand::random()
+    /// Dispatch datagrams from `peer_addr` to `session`, replacing the existing one
+    pub fn insert(&mut self, peer_addr: SocketAddr, session: Arc<KcpSession>) {
+        let conv = session.kcp_socket().lock().conv();
+        self.reserve_conv(conv);
+        self.seen_peers.insert(peer_addr);
+        if let Some(old_session) = self.sessions.insert(peer_addr, KcpSessionUniq(session)) {
+            let old_conv = old_session.kcp_socket().lock().conv();
+            self.release_conv(old_conv);
+        }
+    }
+
+    /// Keep track of a session switching from `old_conv` to `new_conv`
+    pub fn switch_conv(&mut self, old_conv: u32, new_conv: u32) {
+        self.release_conv(old_conv);
+        self.reserve_conv(new_conv);
+    }
+
+    /// Settle on one conv with a peer connecting to us while we are connecting to it
+    ///
+    /// Returns the conv of the session with `peer_addr` if `packet` belongs to the peer's own session. The session
+    /// settles on the conv in place, it isn't replaced by a new one.
+    pub fn resolve_simultaneous_open(&mut self, peer_addr: &SocketAddr, packet: &[u8]) -> Option<u32> {
+        let (old_conv, new_conv) = {
+            let session = self.sessions.get(peer_addr)?;
+            let mut socket = session.kcp_socket().lock();
+            let old_conv = socket.conv();
+            if !socket.resolve_simultaneous_open(packet) {
+                return None;
+            }
+            (old_conv, socket.conv())
+        };
+
+        if new_conv != old_conv {
+            self.switch_conv(old_conv, new_conv);
+        }
+        Some(new_conv)
+    }
+
+    /// What to do with datagrams of unknown convs
+    pub fn unknown_conv_policy(&self) -> UnknownConvPolicy {
+        self.unknown_conv_policy
+    }
+
+    /// Distinct peers seen and peers with live sessions
+    ///
+    /// Every peer address seen is remembered for the lifetime of the manager.
+    pub fn peer_stats(&self) -> PeerStats {
+        PeerStats {
+            total_seen: self.seen_peers.len(),
+            active: self.sessions.len(),
+        }
+    }
+
+    /// Check if datagrams from `peer_addr` are dispatched to a session
+    pub fn contains_peer(&self, peer_addr: &SocketAddr) -> bool {
+        self.sessions.contains_key(peer_addr)
     }
 
     pub fn close_peer(&mut self, peer_addr: SocketAddr) {
-        self.sessions.remove(&peer_addr);
+        if let Some(session) = self.sessions.remove(&peer_addr) {
+            let conv = session.kcp_socket().lock().conv();
+            self.release_conv(conv);
+        }
+    }
+
+    fn reserve_conv(&mut self, conv: u32) {
+        *self.active_convs.entry(conv).or_insert(0) += 1;
+    }
+
+    /// Release `conv` of a closed session, it is quarantined before being allocated again
+    fn release_conv(&mut self, conv: u32) {
+        if let Entry::Occupied(mut occ) = self.active_convs.entry(conv) {
+            *occ.get_mut() -= 1;
+            if *occ.get() == 0 {
+                occ.remove();
+            }
+        }
+        self.quarantine_conv(conv);
+    }
+
+    /// Keep `conv` from being allocated for `conv_reuse_grace`
+    ///
+    /// Late datagrams of a closed session won't be taken by a new session.
+    fn quarantine_conv(&mut self, conv: u32) {
+        if !self.conv_reuse_grace.is_zero() {
+            self.quarantined_convs.insert(conv, self.clock.now());
+        }
     }
 
     pub async fn get_or_create(
@@ -339,6 +649,8 @@ impl KcpSessionManager {
                         old_conv,
                         peer_addr
                     );
+                    self.reserve_conv(conv);
+                    self.release_conv(old_conv);
 
                     Ok((session, true))
                 } else {
@@ -354,8 +666,124 @@ impl KcpSessionManager {
                 );
                 trace!("created session for conv: {}, peer: {}", conv, peer_addr);
                 vac.insert(KcpSessionUniq(session.clone()));
+                self.reserve_conv(conv);
+                self.seen_peers.insert(peer_addr);
                 Ok((session, true))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::ErrorKind,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use kcp::Error as KcpError;
+
+    use super::KcpSessionManager;
+    use crate::{clock::TestClock, config::KcpConfig, conv::ConvAllocator};
+
+    #[test]
+    fn dedup_conv_alloc() {
+        let peer1 = "127.0.0.1:1000".parse::<SocketAddr>().unwrap();
+        let peer2 = "127.0.0.1:1001".parse::<SocketAddr>().unwrap();
+
+        let mut sessions = KcpSessionManager::new(&KcpConfig::default());
+        let conv = sessions.alloc_conv(peer1).unwrap();
+        assert_eq!(sessions.alloc_conv(peer1).unwrap(), conv);
+        assert_ne!(sessions.alloc_conv(peer2).unwrap(), conv);
+
+        let config = KcpConfig {
+            conv_alloc_dedup_window: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut sessions = KcpSessionManager::new(&config);
+        let conv = sessions.alloc_conv(peer1).unwrap();
+        assert_ne!(sessions.alloc_conv(peer1).unwrap(), conv);
+    }
+
+    #[test]
+    fn quarantine_closed_conv() {
+        let peer = "127.0.0.1:1000".parse::<SocketAddr>().unwrap();
+
+        let clock = Arc::new(TestClock::new());
+        let config = KcpConfig {
+            conv_allocator: ConvAllocator::Deterministic { secret: 0xdeadbeef },
+            conv_alloc_dedup_window: Duration::ZERO,
+            conv_reuse_grace: Duration::from_millis(50),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+        let mut sessions = KcpSessionManager::new(&config);
+
+        let conv = sessions.alloc_conv(peer).unwrap();
+        sessions.quarantine_conv(conv);
+        assert_ne!(sessions.alloc_conv(peer).unwrap(), conv);
+
+        clock.advance(Duration::from_millis(60));
+        assert_eq!(sessions.alloc_conv(peer).unwrap(), conv);
+    }
+
+    #[test]
+    fn unique_conv_alloc() {
+        static SEQ: AtomicU32 = AtomicU32::new(0);
+
+        fn source() -> u32 {
+            // 5, 5, 6, 6, ...
+            SEQ.fetch_add(1, Ordering::Relaxed) / 2 + 5
+        }
+
+        let peer1 = "127.0.0.1:1000".parse::<SocketAddr>().unwrap();
+        let peer2 = "127.0.0.1:1001".parse::<SocketAddr>().unwrap();
+
+        let config = KcpConfig {
+            conv_allocator: ConvAllocator::RandomWith { source },
+            conv_reuse_grace: Duration::ZERO,
+            ..Default::default()
+        };
+        let mut sessions = KcpSessionManager::new(&config);
+
+        let conv = sessions.alloc_conv(peer1).unwrap();
+        assert_eq!(conv, 5);
+        sessions.reserve_conv(conv);
+        assert_eq!(sessions.alloc_conv(peer2).unwrap(), 6);
+
+        // Recycled after being released
+        sessions.release_conv(conv);
+        SEQ.store(0, Ordering::Relaxed);
+        let peer3 = "127.0.0.1:1002".parse::<SocketAddr>().unwrap();
+        assert_eq!(sessions.alloc_conv(peer3).unwrap(), 5);
+    }
+
+    #[test]
+    fn conv_alloc_exhausted() {
+        fn source() -> u32 {
+            7
+        }
+
+        let peer1 = "127.0.0.1:1000".parse::<SocketAddr>().unwrap();
+        let peer2 = "127.0.0.1:1001".parse::<SocketAddr>().unwrap();
+
+        let config = KcpConfig {
+            conv_allocator: ConvAllocator::RandomWith { source },
+            ..Default::default()
+        };
+        let mut sessions = KcpSessionManager::new(&config);
+
+        let conv = sessions.alloc_conv(peer1).unwrap();
+        sessions.reserve_conv(conv);
+        // The only conv the source ever gives is taken
+        match sessions.alloc_conv(peer2) {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), ErrorKind::AddrInUse),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+}
@@ -1,36 +1,324 @@
 use std::{
+    collections::{BTreeMap, VecDeque},
     io::{self, ErrorKind, Write},
     net::SocketAddr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     task::{Context, Poll, Waker},
     time::{Duration, Instant},
 };
 
-use futures::future;
+use bytes::Bytes;
+use futures::{future, ready, task::AtomicWaker};
 use kcp::{Error as KcpError, Kcp, KcpResult};
-use log::{error, trace};
-use tokio::{net::UdpSocket, sync::mpsc};
+use log::{error, trace, LevelFilter};
+use spin::Mutex as SpinMutex;
+use tokio::{net::UdpSocket, sync::mpsc, time};
 
-use crate::{utils::now_millis, KcpConfig};
+use crate::{
+    bandwidth::BandwidthShare,
+    clock::Clock,
+    config::{check_mtu, EffectiveConfig, HealthScoreWeights, MtuProbeConfig, MAX_MTU},
+    error::SendWindowFull,
+    priority::{SendClass, SendQueueOrder},
+    rate::RateLimitSource,
+    stats::{segment_size_bucket, CloseReason, KcpStats, SessionSummary, SEGMENT_SIZE_BUCKETS},
+    transform::PacketTransform,
+    utils::now_millis,
+    KcpConfig, KcpNoDelayConfig,
+};
+
+/// `trace!` only if the session's log level allows it
+macro_rules! session_trace {
+    ($socket:expr, $($arg:tt)+) => {
+        if $socket.log_level >= LevelFilter::Trace {
+            #[cfg(test)]
+            test::SESSION_TRACES.with(|traces| traces.set(traces.get() + 1));
+            trace!($($arg)+);
+        }
+    };
+}
+
+/// KCP command of PUSH segments
+const KCP_CMD_PUSH: u8 = 81;
+/// KCP command of ACK segments
+const KCP_CMD_ACK: u8 = 82;
+/// KCP command of window probes, answered with a WINS
+const KCP_CMD_WASK: u8 = 83;
+/// KCP command of window size announcements, ignored by KCP except for the header
+const KCP_CMD_WINS: u8 = 84;
+
+/// `frg` marking keep-alive WINS, KCP always sends WINS with `frg` 0
+const KEEPALIVE_FRG: u8 = 0xff;
+/// `frg` marking WINS sent when closing, telling the peer no more data is coming
+const FIN_FRG: u8 = 0xfe;
+/// `frg` marking WINS sent after writing was shut down, telling the peer no more data is coming
+const SHUTDOWN_FRG: u8 = 0xfd;
+/// `frg` marking WINS padded to the size of an MTU probe, echoing the size in `sn`
+const MTU_PROBE_FRG: u8 = 0xfc;
+/// `frg` marking WINS replying to an MTU probe, echoing its size in `sn`
+const MTU_PROBE_REPLY_FRG: u8 = 0xfb;
+
+/// MTU probing stops narrowing down once the gap is smaller than this
+const MTU_PROBE_MIN_STEP: usize = 32;
+
+/// Fragments KCP accepts in one `send`, bounded by the 8-bit `frg` field and the default receive window
+const MAX_SEND_SEGMENTS: usize = 128;
+
+/// Base backoff between retries of a failed delayed send
+const UDP_SEND_RETRY_BACKOFF: Duration = Duration::from_millis(5);
+
+/// `ENOBUFS` of the platform
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const ENOBUFS: i32 = 105;
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly"
+))]
+const ENOBUFS: i32 = 55;
+#[cfg(windows)]
+const ENOBUFS: i32 = 10055; // WSAENOBUFS
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "dragonfly",
+    windows
+)))]
+const ENOBUFS: i32 = -1;
+
+/// Send failures worth retrying, the others (`EACCES`, `ENETUNREACH`, `EINVAL`, `EMSGSIZE`, ...) won't go away
+fn is_transient_send_error(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::Interrupted || err.raw_os_error() == Some(ENOBUFS)
+}
+
+/// Counters of `UdpOutput`, shared with the delayed sender
+#[derive(Debug, Default)]
+struct UdpOutputStats {
+    /// Datagrams written by KCP
+    datagrams: AtomicU64,
+    /// Retries of failed sends
+    send_retries: AtomicU64,
+    /// Sends returned `WouldBlock` and were delayed
+    send_would_block: AtomicU64,
+    /// Packets sent by the delayed sender
+    delayed_sent: AtomicU64,
+    /// Total time packets waited in the delayed queue (us)
+    delay_total_us: AtomicU64,
+    /// Longest time a packet waited in the delayed queue (us)
+    delay_max_us: AtomicU64,
+    /// Bytes waiting in the delayed queue
+    delay_queued_bytes: AtomicU64,
+    /// PUSH segments written by KCP
+    push_segments: AtomicU64,
+    /// PUSH segments written again, whose `sn` isn't newer than the latest one sent
+    retransmitted_segments: AtomicU64,
+    /// PUSH segments written by KCP, bucketed by payload size
+    segment_sizes: [AtomicU64; SEGMENT_SIZE_BUCKETS],
+    /// Sender waiting for the delayed queue to drain
+    queue_drained: AtomicWaker,
+    /// `flush_all` waiting for the delayed queue to be empty
+    queue_empty: AtomicWaker,
+    /// `wnd` and `una` of the latest segment written by KCP, the unused window and next `sn` it advertised
+    advertised: SpinMutex<Option<(u16, u32)>>,
+    /// Latest `sn` of PUSH segments sent
+    latest_sn: SpinMutex<Option<u32>>,
+    /// Errors the next sends fail with, injected by tests
+    #[cfg(test)]
+    fail_sends: SpinMutex<VecDeque<ErrorKind>>,
+}
+
+impl UdpOutputStats {
+    /// Failure injected by tests in place of the next send
+    #[cfg(test)]
+    fn injected_failure(&self) -> Option<io::Error> {
+        self.fail_sends.lock().pop_front().map(io::Error::from)
+    }
+
+    #[cfg(not(test))]
+    #[inline]
+    fn injected_failure(&self) -> Option<io::Error> {
+        None
+    }
+
+    fn record_delay(&self, delay: Duration) {
+        let delay_us = delay.as_micros() as u64;
+        self.delayed_sent.fetch_add(1, Ordering::Relaxed);
+        self.delay_total_us.fetch_add(delay_us, Ordering::Relaxed);
+        self.delay_max_us.fetch_max(delay_us, Ordering::Relaxed);
+    }
+}
+
+/// The lower of a configured `rate` and a bandwidth pool's `share`, zero shares are ignored
+fn limited_rate(rate: Option<u64>, share: Option<&AtomicU64>) -> Option<u64> {
+    let share = share
+        .map(|share| share.load(Ordering::Relaxed))
+        .filter(|&share| share > 0);
+    match (rate, share) {
+        (Some(rate), Some(share)) => Some(rate.min(share)),
+        (rate, share) => rate.or(share),
+    }
+}
+
+/// Spacing of datagrams for `KcpConfig::max_pps`, `KcpConfig::send_rate_limit`, `KcpConfig::bandwidth_pool` and
+/// `KcpConfig::pacing`
+///
+/// Shared by `UdpOutput` and its delayed sender.
+#[derive(Debug)]
+struct OutputLimiter {
+    /// Shortest interval between datagrams
+    pps_interval: Duration,
+    /// Bytes per second
+    rate: Option<u64>,
+    /// Bytes per second shared by the bandwidth pool, updated by it
+    share: Option<Arc<AtomicU64>>,
+    /// Bytes per second of pacing, updated by `KcpSocket` from its RTT, zero before the first sample
+    pacing: Option<Arc<AtomicU64>>,
+    /// Earliest time the next datagram can be sent
+    next: Instant,
+    /// Time source of the slots, the session's
+    clock: Arc<dyn Clock>,
+}
+
+impl OutputLimiter {
+    /// `None` if nothing is limited, a zero limit is no limit
+    fn new(
+        clock: Arc<dyn Clock>,
+        max_pps: Option<u32>,
+        rate: Option<u64>,
+        share: Option<Arc<AtomicU64>>,
+        pacing: Option<Arc<AtomicU64>>,
+    ) -> Option<OutputLimiter> {
+        let max_pps = max_pps.filter(|&max_pps| max_pps > 0);
+        let rate = rate.filter(|&rate| rate > 0);
+        if max_pps.is_none() && rate.is_none() && share.is_none() && pacing.is_none() {
+            return None;
+        }
+
+        Some(OutputLimiter {
+            pps_interval: max_pps.map_or(Duration::ZERO, |max_pps| Duration::from_secs(1) / max_pps),
+            rate,
+            share,
+            pacing,
+            next: clock.now(),
+            clock,
+        })
+    }
+
+    /// Interval to leave after sending a datagram of `len` bytes
+    fn spacing(&self, len: usize) -> Duration {
+        let rate = limited_rate(limited_rate(self.rate, self.share.as_deref()), self.pacing.as_deref());
+        let rate_interval = match rate {
+            Some(rate) => Duration::from_nanos((len as u64 * 1_000_000_000 / rate).max(1)),
+            None => Duration::ZERO,
+        };
+        self.pps_interval.max(rate_interval)
+    }
+
+    /// Take the slot of a datagram of `len` bytes if it has come
+    fn try_acquire(&mut self, len: usize) -> bool {
+        let now = self.clock.now();
+        if now < self.next {
+            return false;
+        }
+        self.next = now + self.spacing(len);
+        true
+    }
+
+    /// Take the next slot for a datagram of `len` bytes, returning how long until it comes
+    fn acquire(&mut self, len: usize) -> Duration {
+        let now = self.clock.now();
+        let slot = self.next.max(now);
+        self.next = slot + self.spacing(len);
+        slot - now
+    }
+}
 
 /// Writer for sending packets to the underlying UdpSocket
 struct UdpOutput {
     socket: Arc<UdpSocket>,
-    target_addr: SocketAddr,
-    delay_tx: mpsc::UnboundedSender<Vec<u8>>,
+    /// Shared with `KcpSocket` for migrating to another peer address
+    target_addr: Arc<SpinMutex<SocketAddr>>,
+    delay_tx: mpsc::UnboundedSender<(Vec<u8>, SocketAddr, Instant)>,
+    send_retries: usize,
+    max_queue: Option<usize>,
+    stats: Arc<UdpOutputStats>,
+    transform: Option<Arc<dyn PacketTransform>>,
+    limiter: Option<Arc<SpinMutex<OutputLimiter>>>,
 }
 
 impl UdpOutput {
     /// Create a new Writer for writing packets to UdpSocket
-    pub fn new(socket: Arc<UdpSocket>, target_addr: SocketAddr) -> UdpOutput {
-        let (delay_tx, mut delay_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    ///
+    /// Failed delayed sends are retried `send_retries` times, packets beyond `max_queue` bytes are dropped.
+    /// Datagrams are spaced as `limiter` requires.
+    pub fn new(
+        socket: Arc<UdpSocket>,
+        target_addr: Arc<SpinMutex<SocketAddr>>,
+        send_retries: usize,
+        max_queue: Option<usize>,
+        stats: Arc<UdpOutputStats>,
+        transform: Option<Arc<dyn PacketTransform>>,
+        limiter: Option<OutputLimiter>,
+    ) -> UdpOutput {
+        let (delay_tx, mut delay_rx) = mpsc::unbounded_channel::<(Vec<u8>, SocketAddr, Instant)>();
+        let limiter = limiter.map(|limiter| Arc::new(SpinMutex::new(limiter)));
 
         {
             let socket = socket.clone();
+            let stats = stats.clone();
+            let limiter = limiter.clone();
             tokio::spawn(async move {
-                while let Some(buf) = delay_rx.recv().await {
-                    if let Err(err) = socket.send_to(&buf, target_addr).await {
-                        error!("[SEND] UDP delayed send failed, error: {}", err);
+                // Packets are sent to the address they were queued for, even if the session has migrated since
+                while let Some((buf, target_addr, queued_time)) = delay_rx.recv().await {
+                    if let Some(ref limiter) = limiter {
+                        let wait = limiter.lock().acquire(buf.len());
+                        time::sleep(wait).await;
+                    }
+
+                    let mut retried = 0;
+                    loop {
+                        let result = match stats.injected_failure() {
+                            Some(err) => Err(err),
+                            None => socket.send_to(&buf, target_addr).await,
+                        };
+                        match result {
+                            Ok(..) => {
+                                stats.record_delay(queued_time.elapsed());
+                                break;
+                            }
+                            Err(err) if retried < send_retries && is_transient_send_error(&err) => {
+                                retried += 1;
+                                stats.send_retries.fetch_add(1, Ordering::Relaxed);
+                                trace!(
+                                    "[SEND] UDP delayed send failed, error: {}, retrying {}/{}",
+                                    err,
+                                    retried,
+                                    send_retries
+                                );
+                                time::sleep(UDP_SEND_RETRY_BACKOFF * retried as u32).await;
+                            }
+                            Err(err) => {
+                                error!("[SEND] UDP delayed send failed, error: {}", err);
+                                break;
+                            }
+                        }
+                    }
+
+                    let queued = stats.delay_queued_bytes.fetch_sub(buf.len() as u64, Ordering::Relaxed);
+                    stats.queue_drained.wake();
+                    if queued == buf.len() as u64 {
+                        stats.queue_empty.wake();
                     }
                 }
             });
@@ -40,22 +328,120 @@ impl UdpOutput {
             socket,
             target_addr,
             delay_tx,
+            send_retries,
+            max_queue,
+            stats,
+            transform,
+            limiter,
         }
     }
+
+    /// Queue `buf` for the delayed sender
+    ///
+    /// Dropped if the queue is full, KCP will retransmit it.
+    fn delay_send(&self, buf: &[u8], target_addr: SocketAddr) {
+        let queued = self.stats.delay_queued_bytes.load(Ordering::Relaxed) as usize;
+        if matches!(self.max_queue, Some(max) if queued + buf.len() > max) {
+            trace!(
+                "[SEND] UDP delayed queue full, queued: {} bytes, packet.size: {} bytes dropped",
+                queued,
+                buf.len()
+            );
+            return;
+        }
+
+        self.stats
+            .delay_queued_bytes
+            .fetch_add(buf.len() as u64, Ordering::Relaxed);
+        self.delay_tx
+            .send((buf.to_owned(), target_addr, Instant::now()))
+            .expect("channel closed unexpectly");
+    }
+
+    /// Count PUSH segments, their sizes and retransmissions of them
+    fn record_segments(&mut self, buf: &[u8]) {
+        if buf.len() >= kcp::KCP_OVERHEAD {
+            let wnd = u16::from_le_bytes([buf[6], buf[7]]);
+            let una = u32::from_le_bytes([buf[16], buf[17], buf[18], buf[19]]);
+            *self.stats.advertised.lock() = Some((wnd, una));
+        }
+
+        let mut latest_sn = self.stats.latest_sn.lock();
+        walk_segments(buf, |header| {
+            if header[4] != KCP_CMD_PUSH {
+                return;
+            }
+            self.stats.push_segments.fetch_add(1, Ordering::Relaxed);
+
+            let len = u32::from_le_bytes([header[20], header[21], header[22], header[23]]) as usize;
+            self.stats.segment_sizes[segment_size_bucket(len)].fetch_add(1, Ordering::Relaxed);
+
+            let sn = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+            match *latest_sn {
+                Some(latest) if (sn.wrapping_sub(latest) as i32) <= 0 => {
+                    self.stats.retransmitted_segments.fetch_add(1, Ordering::Relaxed);
+                }
+                _ => *latest_sn = Some(sn),
+            }
+        });
+    }
 }
 
 impl Write for UdpOutput {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.socket.try_send_to(buf, self.target_addr) {
-            Ok(n) => Ok(n),
+        self.stats.datagrams.fetch_add(1, Ordering::Relaxed);
+        self.record_segments(buf);
+
+        // KCP expects the length it wrote, not the length sent
+        let len = buf.len();
+        let encoded;
+        let buf = match self.transform.as_deref() {
+            Some(transform) => {
+                encoded = encode_packet(transform, buf.to_owned());
+                &encoded
+            }
+            None => buf,
+        };
+
+        let target_addr = *self.target_addr.lock();
+
+        // Queued datagrams go first, in order
+        if let Some(ref limiter) = self.limiter {
+            let queued = self.stats.delay_queued_bytes.load(Ordering::Relaxed);
+            if queued > 0 || !limiter.lock().try_acquire(buf.len()) {
+                trace!("[SEND] output limited, packet.size: {} bytes, delayed send", buf.len());
+                self.delay_send(buf, target_addr);
+                return Ok(len);
+            }
+        }
+
+        let result = match self.stats.injected_failure() {
+            Some(err) => Err(err),
+            None => self.socket.try_send_to(buf, target_addr),
+        };
+        match result {
+            Ok(..) => Ok(len),
             Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
                 // send return EAGAIN
                 // ignored as packet was lost in transmission
                 trace!("[SEND] UDP send EAGAIN, packet.size: {} bytes, delayed send", buf.len());
+                self.stats.send_would_block.fetch_add(1, Ordering::Relaxed);
 
-                self.delay_tx.send(buf.to_owned()).expect("channel closed unexpectly");
+                self.delay_send(buf, target_addr);
+                Ok(len)
+            }
+            Err(ref err) if self.send_retries > 0 && is_transient_send_error(err) => {
+                // A transient failure (ENOBUFS, ...)
+                // let the delayed sender retry it instead of failing the whole flush
+                self.stats.send_retries.fetch_add(1, Ordering::Relaxed);
+                trace!(
+                    "[SEND] UDP send failed, error: {}, packet.size: {} bytes, delayed send",
+                    err,
+                    buf.len()
+                );
 
-                Ok(buf.len())
+                self.delay_send(buf, target_addr);
+                Ok(len)
             }
             Err(err) => Err(err),
         }
@@ -66,17 +452,304 @@ impl Write for UdpOutput {
     }
 }
 
+fn encode_packet(transform: &dyn PacketTransform, mut packet: Vec<u8>) -> Vec<u8> {
+    transform.encode(&mut packet);
+    packet
+}
+
+/// Approximation of KCP's RTT estimation from the ACKs received, KCP doesn't expose its own
+///
+/// Samples are taken with the session's clock rather than KCP's `current`, so it may drift from KCP's values.
+#[derive(Debug, Default)]
+struct RttEstimator {
+    srtt: Option<u32>,
+    rttval: u32,
+}
+
+impl RttEstimator {
+    /// KCP's initial RTO
+    const RTO_DEFAULT: u32 = 200;
+    const RTO_MAX: u32 = 60000;
+
+    fn sample(&mut self, rtt: u32) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttval = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = rtt.abs_diff(srtt);
+                self.rttval = (3 * self.rttval + delta) / 4;
+                self.srtt = Some(((7 * srtt as u64 + rtt as u64) / 8).max(1) as u32);
+            }
+        }
+    }
+
+    fn rto(&self, nodelay: &KcpNoDelayConfig) -> u32 {
+        let srtt = match self.srtt {
+            Some(srtt) => srtt,
+            None => return RttEstimator::RTO_DEFAULT,
+        };
+        let interval = nodelay.interval.clamp(10, 5000) as u32;
+        (srtt + interval.max(4 * self.rttval)).clamp(RttEstimator::min_rto(nodelay), RttEstimator::RTO_MAX)
+    }
+
+    /// Minimum RTO KCP applies for `nodelay`
+    fn min_rto(nodelay: &KcpNoDelayConfig) -> u32 {
+        if nodelay.nodelay {
+            30
+        } else {
+            100
+        }
+    }
+}
+
+/// State of upward MTU probing
+#[derive(Debug)]
+struct MtuProber {
+    config: MtuProbeConfig,
+    /// Size and send time of the probe waiting for reply
+    in_flight: Option<(usize, Instant)>,
+    /// Smallest probe size that failed
+    ceiling: Option<usize>,
+    last_probe: Instant,
+}
+
+impl MtuProber {
+    fn new(config: MtuProbeConfig, now: Instant) -> MtuProber {
+        MtuProber {
+            config,
+            in_flight: None,
+            ceiling: None,
+            last_probe: now,
+        }
+    }
+
+    /// Size of the next probe above `mtu`
+    ///
+    /// Halves the gap to the smallest failed size, and starts over from `max_mtu` once it is narrow enough,
+    /// in case the path MTU has increased.
+    fn next_size(&mut self, mtu: usize) -> Option<usize> {
+        let max_mtu = self.config.max_mtu.min(MAX_MTU);
+        match self.ceiling {
+            Some(ceiling) if ceiling > mtu + MTU_PROBE_MIN_STEP => Some((mtu + ceiling) / 2),
+            _ => {
+                self.ceiling = None;
+                if max_mtu > mtu {
+                    Some(max_mtu)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// ACK segments carried by a datagram
+struct InputAcks {
+    count: usize,
+    /// Timestamp echoed by the last ACK
+    last_ts: Option<u32>,
+}
+
+/// Call `f` with the header of every segment in a datagram
+fn walk_segments<F: FnMut(&[u8])>(mut buf: &[u8], mut f: F) {
+    // conv(4) cmd(1) frg(1) wnd(2) ts(4) sn(4) una(4) len(4)
+    while buf.len() >= kcp::KCP_OVERHEAD {
+        f(&buf[..kcp::KCP_OVERHEAD]);
+
+        let len = u32::from_le_bytes([buf[20], buf[21], buf[22], buf[23]]) as usize;
+        buf = &buf[kcp::KCP_OVERHEAD..];
+        if buf.len() < len {
+            break;
+        }
+        buf = &buf[len..];
+    }
+}
+
+/// Copy of a datagram with the conv of every segment replaced by `conv`
+fn with_conv(buf: &[u8], conv: u32) -> Vec<u8> {
+    let mut buf = buf.to_owned();
+    let mut offset = 0;
+    while buf.len() - offset >= kcp::KCP_OVERHEAD {
+        kcp::set_conv(&mut buf[offset..], conv);
+
+        let header = &buf[offset..offset + kcp::KCP_OVERHEAD];
+        let len = u32::from_le_bytes([header[20], header[21], header[22], header[23]]) as usize;
+        offset = (offset + kcp::KCP_OVERHEAD).saturating_add(len).min(buf.len());
+    }
+    buf
+}
+
+/// Check if a datagram carries a WINS marked with `frg`
+fn has_marked_wins(buf: &[u8], frg: u8) -> bool {
+    let mut found = false;
+    walk_segments(buf, |header| {
+        if header[4] == KCP_CMD_WINS && header[5] == frg {
+            found = true;
+        }
+    });
+    found
+}
+
+/// Check if a datagram carries a FIN, sent by a closing session
+pub(crate) fn is_fin(buf: &[u8]) -> bool {
+    has_marked_wins(buf, FIN_FRG)
+}
+
+/// Check if a datagram carries a SHUTDOWN, sent by a session which shut down writing
+fn is_shutdown(buf: &[u8]) -> bool {
+    has_marked_wins(buf, SHUTDOWN_FRG)
+}
+
+/// PUSH segments with a `sn` below this may come from a new session, KCP's default send window
+const SESSION_START_MAX_SN: u32 = 32;
+
+/// Check if a datagram plausibly starts a session: asking for a conv, carrying one of the first data segments, or
+/// from a peer which hasn't received anything yet (`una` 0)
+///
+/// The datagram with `sn` 0 may be lost, the following ones still start the session.
+pub(crate) fn is_session_start(buf: &[u8]) -> bool {
+    if buf.len() < kcp::KCP_OVERHEAD {
+        return false;
+    }
+    if kcp::get_conv(buf) == 0 {
+        return true;
+    }
+
+    let mut start = false;
+    walk_segments(buf, |header| {
+        // conv(4) cmd(1) frg(1) wnd(2) ts(4) sn(4) una(4) len(4)
+        let sn = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+        let una = u32::from_le_bytes([header[16], header[17], header[18], header[19]]);
+        start |= (header[4] == KCP_CMD_PUSH && sn < SESSION_START_MAX_SN) || una == 0;
+    });
+    start
+}
+
+/// FIN of `conv`, for resetting a session without having one
+pub(crate) fn fin_packet(conv: u32) -> Vec<u8> {
+    let mut packet = vec![0u8; kcp::KCP_OVERHEAD];
+    packet[..4].copy_from_slice(&conv.to_le_bytes());
+    packet[4] = KCP_CMD_WINS;
+    packet[5] = FIN_FRG;
+    packet[8..12].copy_from_slice(&now_millis().to_le_bytes());
+    packet
+}
+
+/// Scan ACK segments in a datagram by walking segment headers, without handing it to KCP
+fn scan_acks(buf: &[u8]) -> InputAcks {
+    let mut acks = InputAcks {
+        count: 0,
+        last_ts: None,
+    };
+
+    walk_segments(buf, |header| {
+        if header[4] == KCP_CMD_ACK {
+            acks.count += 1;
+            acks.last_ts = Some(u32::from_le_bytes([header[8], header[9], header[10], header[11]]));
+        }
+    });
+
+    acks
+}
+
 #[derive(Debug)]
 pub struct KcpSocket {
     kcp: Kcp<UdpOutput>,
     last_update: Instant,
+    /// Since when the idle timer is frozen
+    idle_frozen: Option<Instant>,
     socket: Arc<UdpSocket>,
     flush_write: bool,
     flush_ack_input: bool,
     sent_first: bool,
+    /// Conv the peer gave up for ours on a simultaneous open, its datagrams are input as ours
+    abandoned_peer_conv: Option<u32>,
+    /// `una` of the latest datagram input, no later than KCP's `snd_una`
+    peer_una: u32,
     pending_sender: Option<Waker>,
     pending_receiver: Option<Waker>,
+    pending_acked: Option<Waker>,
     closed: bool,
+    /// Closed with data unacknowledged, the FIN waits for the ACKs
+    fin_pending: bool,
+    /// Received a FIN, the peer has closed after all its data was acknowledged
+    peer_closed: bool,
+    /// Writing shut down by `shutdown_write`
+    write_closed: bool,
+    /// When SHUTDOWN was last sent to the peer
+    shutdown_sent: Option<Instant>,
+    /// Received a SHUTDOWN, the peer shut down writing after all its data was acknowledged
+    peer_write_closed: bool,
+    messages_received: u64,
+    output_stats: Arc<UdpOutputStats>,
+    max_input_acks: Option<usize>,
+    ack_flood_dropped: u64,
+    nodelay: KcpNoDelayConfig,
+    pending_nodelay: Option<KcpNoDelayConfig>,
+    no_congestion_control: bool,
+    log_level: LevelFilter,
+    last_input: Instant,
+    keepalive_interval: Option<Duration>,
+    last_rtt_sample: Option<u32>,
+    max_rtt_sample: Option<u32>,
+    rtt: RttEstimator,
+    segments_received: u64,
+    send_calls: u64,
+    send_stalls: u64,
+    pending_message_segments: VecDeque<usize>,
+    pending_message_segments_total: usize,
+    last_update_flushed: bool,
+    peak_memory: usize,
+    max_memory: Option<usize>,
+    max_buffer_latency: Option<Duration>,
+    oldest_unflushed: Option<Instant>,
+    /// Next `sn` expected in order, mirrors KCP's `rcv_nxt`
+    recv_next_sn: u32,
+    /// `sn` received out of order, with their `ts`
+    recv_ahead_sn: BTreeMap<u32, u32>,
+    duplicate_segments: u64,
+    /// Most segments observed overtaking an earlier sent one
+    max_reorder_distance: u32,
+    adaptive_fast_resend: bool,
+    /// Fast resend threshold applied to KCP
+    fast_resend: u32,
+    /// Last time an ACK acknowledged pending segments, or pending segments appeared
+    last_ack_progress: Instant,
+    configured_mtu: usize,
+    target_addr: Arc<SpinMutex<SocketAddr>>,
+    max_output_queue: Option<usize>,
+    mtu_prober: Option<MtuProber>,
+    /// Last time KCP emitted any datagram, and the datagram counter then
+    last_output: (Instant, u64),
+    created: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    packet_transform: Option<Arc<dyn PacketTransform>>,
+    write_timeout: Option<Duration>,
+    /// Last time the delayed output queue was empty or made progress, with the packets it had sent by then
+    output_progress: (Instant, u64),
+    write_timed_out: bool,
+    close_on_dead_link: bool,
+    dead: bool,
+    connect_timeout: Option<Duration>,
+    /// Received a valid segment from the peer
+    connected: bool,
+    connect_timed_out: bool,
+    /// Messages of `send_with_class` waiting for the send window, indexed by class
+    class_queues: [VecDeque<Vec<u8>>; SendClass::COUNT],
+    class_queued_bytes: usize,
+    send_queue_order: SendQueueOrder,
+    clock: Arc<dyn Clock>,
+    max_pps: Option<u32>,
+    send_rate_limit: Option<u64>,
+    bandwidth_share: Option<BandwidthShare>,
+    /// Shared with `OutputLimiter`, `None` unless `KcpConfig::pacing` or `KcpConfig::cwnd_pacing`
+    pacing_rate: Option<Arc<AtomicU64>>,
+    cwnd_pacing: bool,
+    /// Segments KCP keeps in flight while more are waiting, its congestion window capped by the send windows
+    cwnd_estimate: Option<usize>,
 }
 
 impl KcpSocket {
@@ -87,74 +760,234 @@ impl KcpSocket {
         target_addr: SocketAddr,
         stream: bool,
     ) -> KcpResult<KcpSocket> {
-        let output = UdpOutput::new(socket.clone(), target_addr);
+        // Receive buffers are sized for one UDP datagram
+        check_mtu(c.mtu)?;
+
+        let output_stats = Arc::new(UdpOutputStats::default());
+        let bandwidth_share = c.bandwidth_pool.as_ref().map(|pool| pool.join(1));
+        let pacing_rate = (c.pacing || c.cwnd_pacing).then(|| Arc::new(AtomicU64::new(0)));
+        let target_addr = Arc::new(SpinMutex::new(target_addr));
+        let output = UdpOutput::new(
+            socket.clone(),
+            target_addr.clone(),
+            c.udp_send_retries,
+            c.max_output_queue,
+            output_stats.clone(),
+            c.packet_transform.clone(),
+            OutputLimiter::new(
+                c.clock.clone(),
+                c.max_pps,
+                c.send_rate_limit,
+                bandwidth_share.as_ref().map(|share| share.rate().clone()),
+                pacing_rate.clone(),
+            ),
+        );
         let mut kcp = if stream {
             Kcp::new_stream(conv, output)
         } else {
             Kcp::new(conv, output)
         };
-        c.apply_config(&mut kcp);
+        c.apply_config(&mut kcp)?;
 
         // Ask server to allocate one
         if conv == 0 {
             kcp.input_conv();
         }
 
-        kcp.update(now_millis())?;
+        let now = c.clock.now();
+        kcp.update(c.clock.now_millis())?;
 
         Ok(KcpSocket {
             kcp,
-            last_update: Instant::now(),
+            last_update: now,
+            idle_frozen: None,
             socket,
             flush_write: c.flush_write,
             flush_ack_input: c.flush_acks_input,
             sent_first: false,
+            abandoned_peer_conv: None,
+            peer_una: 0,
             pending_sender: None,
             pending_receiver: None,
+            pending_acked: None,
             closed: false,
+            fin_pending: false,
+            peer_closed: false,
+            write_closed: false,
+            shutdown_sent: None,
+            peer_write_closed: false,
+            messages_received: 0,
+            output_stats,
+            max_input_acks: c.max_input_acks,
+            ack_flood_dropped: 0,
+            nodelay: c.nodelay,
+            pending_nodelay: None,
+            no_congestion_control: c.no_congestion_control,
+            log_level: c.log_level,
+            last_input: now,
+            keepalive_interval: c.keepalive_interval,
+            last_rtt_sample: None,
+            max_rtt_sample: None,
+            rtt: RttEstimator::default(),
+            segments_received: 0,
+            send_calls: 0,
+            send_stalls: 0,
+            pending_message_segments: VecDeque::new(),
+            pending_message_segments_total: 0,
+            last_update_flushed: false,
+            peak_memory: 0,
+            max_memory: c.max_session_memory,
+            max_buffer_latency: c.max_buffer_latency,
+            oldest_unflushed: None,
+            recv_next_sn: 0,
+            recv_ahead_sn: BTreeMap::new(),
+            duplicate_segments: 0,
+            max_reorder_distance: 0,
+            adaptive_fast_resend: c.adaptive_fast_resend,
+            fast_resend: c.nodelay.resend.max(0) as u32,
+            last_ack_progress: now,
+            configured_mtu: c.mtu,
+            target_addr,
+            max_output_queue: c.max_output_queue,
+            mtu_prober: c.mtu_probe.map(|config| MtuProber::new(config, now)),
+            last_output: (now, 0),
+            created: now,
+            bytes_sent: 0,
+            bytes_received: 0,
+            packet_transform: c.packet_transform.clone(),
+            write_timeout: c.write_timeout,
+            output_progress: (now, 0),
+            write_timed_out: false,
+            close_on_dead_link: c.dead_link.is_some(),
+            dead: false,
+            connect_timeout: c.connect_timeout,
+            connected: false,
+            connect_timed_out: false,
+            class_queues: Default::default(),
+            class_queued_bytes: 0,
+            send_queue_order: c.send_queue_order,
+            clock: c.clock.clone(),
+            max_pps: c.max_pps.filter(|&max_pps| max_pps > 0),
+            send_rate_limit: c.send_rate_limit.filter(|&rate| rate > 0),
+            bandwidth_share,
+            pacing_rate,
+            cwnd_pacing: c.cwnd_pacing,
+            cwnd_estimate: None,
         })
     }
 
+    /// Time passed since `since` by the session's clock
+    fn elapsed(&self, since: Instant) -> Duration {
+        self.clock.now().saturating_duration_since(since)
+    }
+
+    /// Current instant of the session's clock
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
     /// Call every time you got data from transmission
     pub fn input(&mut self, buf: &[u8]) -> KcpResult<bool> {
+        // Sent before the peer switched to our conv, its retransmissions keep the conv it gave up
+        let adopted;
+        let buf = if buf.len() >= kcp::KCP_OVERHEAD && self.abandoned_peer_conv == Some(kcp::get_conv(buf)) {
+            adopted = with_conv(buf, self.kcp.conv());
+            &adopted[..]
+        } else {
+            buf
+        };
+
+        // Reject floods before KCP walks them, a datagram of the session's MTU holds no more ACKs than this
+        let max_acks = self.max_input_acks.unwrap_or(self.kcp.mtu() / kcp::KCP_OVERHEAD);
+        let acks = scan_acks(buf);
+        if acks.count > max_acks {
+            session_trace!(self, "[INPUT] ACKs={} exceeded limit={}, dropped", acks.count, max_acks);
+            self.ack_flood_dropped += 1;
+            return Ok(false);
+        }
+
+        let wait_snd = self.kcp.wait_snd();
         match self.kcp.input(buf) {
             Ok(..) => {}
             Err(KcpError::ConvInconsistent(expected, actual)) => {
-                trace!("[INPUT] Conv expected={} actual={} ignored", expected, actual);
+                session_trace!(self, "[INPUT] Conv expected={} actual={} ignored", expected, actual);
                 return Ok(false);
             }
             Err(err) => return Err(err),
         }
-        self.last_update = Instant::now();
+        self.connected = true;
+        self.last_update = self.clock.now();
+        self.last_input = self.last_update;
+        // conv(4) cmd(1) frg(1) wnd(2) ts(4) sn(4) una(4) len(4)
+        let una = u32::from_le_bytes([buf[16], buf[17], buf[18], buf[19]]);
+        if (una.wrapping_sub(self.peer_una) as i32) > 0 {
+            self.peer_una = una;
+        }
+        if self.kcp.wait_snd() < wait_snd {
+            self.last_ack_progress = self.last_update;
+        }
+
+        self.record_duplicate_segments(buf);
+        self.on_mtu_probe_reply(buf);
+        self.reply_mtu_probe(buf);
+        if !self.peer_closed && is_fin(buf) {
+            session_trace!(self, "[INPUT] FIN received, peer closed");
+            self.on_peer_closed();
+        }
+        if !self.peer_write_closed && is_shutdown(buf) {
+            session_trace!(self, "[INPUT] SHUTDOWN received, peer shut down writing");
+            self.peer_write_closed = true;
+            if let Some(w) = self.pending_receiver.take() {
+                w.wake();
+            }
+        }
+
+        // ACK echoes the timestamp of the acknowledged segment, the same way KCP samples RTT
+        if let Some(ts) = acks.last_ts {
+            let rtt = self.clock.now_millis().wrapping_sub(ts) as i32;
+            if rtt >= 0 {
+                let rtt = rtt as u32;
+                self.last_rtt_sample = Some(rtt);
+                self.max_rtt_sample = self.max_rtt_sample.max(Some(rtt));
+                self.rtt.sample(rtt);
+            }
+        }
 
         if self.flush_ack_input {
             self.kcp.flush_ack()?;
         }
 
+        // Window opened by ACKs goes to prioritized messages before waking senders
+        self.send_queued_classes()?;
+        self.record_memory_usage();
+
         Ok(self.try_wake_pending_waker())
     }
 
     /// Call if you want to send some data
-    pub fn poll_send(&mut self, cx: &mut Context<'_>, mut buf: &[u8]) -> Poll<KcpResult<usize>> {
-        if self.closed {
+    pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        if let Some(err) = self.timed_out_error() {
+            return Err(err.into()).into();
+        }
+        if self.closed || self.peer_closed || self.write_closed {
             return Err(io::Error::from(ErrorKind::BrokenPipe).into()).into();
         }
 
+        self.send_calls += 1;
+
         // If:
         //     1. Have sent the first packet (asking for conv)
         //     2. Too many pending packets
-        if self.sent_first
-            && (self.kcp.wait_snd() >= self.kcp.snd_wnd() as usize
-                || self.kcp.wait_snd() >= self.kcp.rmt_wnd() as usize
-                || self.kcp.waiting_conv())
-        {
-            trace!(
+        if self.sent_first && (self.is_window_stalled() || self.kcp.waiting_conv()) {
+            session_trace!(
+                self,
                 "[SEND] waitsnd={} sndwnd={} rmtwnd={} excceeded or waiting conv={}",
                 self.kcp.wait_snd(),
                 self.kcp.snd_wnd(),
                 self.kcp.rmt_wnd(),
                 self.kcp.waiting_conv()
             );
+            self.send_stalls += 1;
 
             if let Some(waker) = self.pending_sender.replace(cx.waker().clone()) {
                 if !cx.waker().will_wake(&waker) {
@@ -164,24 +997,128 @@ impl KcpSocket {
             return Poll::Pending;
         }
 
+        if self.is_output_queue_full() {
+            session_trace!(self, "[SEND] UDP delayed queue full, waiting for it to drain");
+            self.output_stats.queue_drained.register(cx.waker());
+            // Drained before being registered
+            if self.is_output_queue_full() {
+                return Poll::Pending;
+            }
+        }
+
+        self.send_now(buf).into()
+    }
+
+    /// Packets waiting for the delayed sender reached `KcpConfig::max_output_queue`
+    pub fn is_output_queue_full(&self) -> bool {
+        let queued = self.output_stats.delay_queued_bytes.load(Ordering::Relaxed) as usize;
+        matches!(self.max_output_queue, Some(max) if queued >= max)
+    }
+
+    /// Send without waiting, fails with `SendWindowFull` if the window is stalled unless `ignore_window`
+    ///
+    /// Ignoring the window queues data in KCP beyond it, which grows without bound if the peer doesn't keep up.
+    pub fn try_send(&mut self, buf: &[u8], ignore_window: bool) -> KcpResult<usize> {
+        if let Some(err) = self.timed_out_error() {
+            return Err(err.into());
+        }
+        if self.closed || self.peer_closed || self.write_closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe).into());
+        }
+
+        self.send_calls += 1;
+
+        if self.sent_first && self.kcp.waiting_conv() {
+            self.send_stalls += 1;
+            return Err(io::Error::from(ErrorKind::WouldBlock).into());
+        }
+        if self.sent_first && !ignore_window && self.is_window_stalled() {
+            self.send_stalls += 1;
+            return Err(SendWindowFull.into());
+        }
+
+        self.send_now(buf)
+    }
+
+    /// Send a message of priority `class`, queued if the send window is full
+    ///
+    /// Queued messages are sent before those waiting in `poll_send`, by class, then in `send_queue_order`.
+    pub fn send_with_class(&mut self, buf: &[u8], class: SendClass) -> KcpResult<usize> {
+        if let Some(err) = self.timed_out_error() {
+            return Err(err.into());
+        }
+        if self.closed || self.peer_closed || self.write_closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe).into());
+        }
+
+        let queue = &mut self.class_queues[class as usize];
+        if let SendQueueOrder::Lifo { drop_superseded: true } = self.send_queue_order {
+            let superseded = queue.drain(..).map(|message| message.len()).sum::<usize>();
+            self.class_queued_bytes -= superseded;
+        }
+        queue.push_back(buf.to_owned());
+        self.class_queued_bytes += buf.len();
+        self.send_queued_classes()?;
+        self.record_memory_usage();
+        Ok(buf.len())
+    }
+
+    /// Hand messages queued by class to KCP while the send window allows, higher classes first
+    fn send_queued_classes(&mut self) -> KcpResult<()> {
+        while !(self.sent_first && (self.is_window_stalled() || self.kcp.waiting_conv())) {
+            let order = self.send_queue_order;
+            let message = match self.class_queues.iter_mut().find_map(|queue| match order {
+                SendQueueOrder::Fifo => queue.pop_front(),
+                SendQueueOrder::Lifo { .. } => queue.pop_back(),
+            }) {
+                Some(message) => message,
+                None => break,
+            };
+            self.class_queued_bytes -= message.len();
+            self.send_now(&message)?;
+        }
+        Ok(())
+    }
+
+    fn send_now(&mut self, mut buf: &[u8]) -> KcpResult<usize> {
         if !self.sent_first && self.kcp.waiting_conv() && buf.len() > self.kcp.mss() as usize {
             buf = &buf[..self.kcp.mss() as usize];
         }
 
+        if self.kcp.wait_snd() == 0 {
+            self.last_ack_progress = self.clock.now();
+        }
+
         let n = self.kcp.send(buf)?;
         self.sent_first = true;
+        self.bytes_sent += n as u64;
 
-        if self.kcp.wait_snd() >= self.kcp.snd_wnd() as usize || self.kcp.wait_snd() >= self.kcp.rmt_wnd() as usize {
+        if !self.kcp.is_stream() {
+            // KCP splits a message into segments of MSS
+            let mss = self.kcp.mss();
+            let segments = n.div_ceil(mss).max(1);
+            self.pending_message_segments.push_back(segments);
+            self.pending_message_segments_total += segments;
+        }
+
+        if self.oldest_unflushed.is_none() {
+            self.oldest_unflushed = Some(self.clock.now());
+        }
+
+        if self.is_window_stalled() {
             self.kcp.flush()?;
+            self.oldest_unflushed = None;
         }
 
-        self.last_update = Instant::now();
+        self.record_memory_usage();
+        self.last_update = self.clock.now();
 
         if self.flush_write {
             self.kcp.flush()?;
+            self.oldest_unflushed = None;
         }
 
-        Ok(n).into()
+        Ok(n)
     }
 
     /// Call if you want to send some data
@@ -195,23 +1132,58 @@ impl KcpSocket {
         if self.closed {
             return Ok(0);
         }
-        self.kcp.recv(buf)
+        let n = self.kcp.recv(buf)?;
+        self.on_message_received(n);
+        Ok(n)
+    }
+
+    /// Receive all messages that are ready
+    pub fn recv_ready_messages(&mut self) -> Vec<Bytes> {
+        let mut messages = Vec::new();
+        if self.closed {
+            return messages;
+        }
+
+        while let Ok(size) = self.kcp.peeksize() {
+            let mut message = vec![0u8; size];
+            match self.kcp.recv(&mut message) {
+                Ok(n) => {
+                    message.truncate(n);
+                    self.on_message_received(n);
+                    messages.push(Bytes::from(message));
+                }
+                Err(..) => break,
+            }
+        }
+        messages
     }
 
     pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if self.connect_timed_out {
+            return Err(io::Error::new(ErrorKind::TimedOut, "connect timed out").into()).into();
+        }
+        if self.dead {
+            return Err(io::Error::new(ErrorKind::TimedOut, "dead link").into()).into();
+        }
         if self.closed {
             return Ok(0).into();
         }
 
         match self.kcp.recv(buf) {
             e @ (Ok(0) | Err(KcpError::RecvQueueEmpty) | Err(KcpError::ExpectingFragment)) => {
-                trace!(
+                session_trace!(
+                    self,
                     "[RECV] rcvwnd={} peeksize={} r={:?}",
                     self.kcp.rcv_wnd(),
                     self.kcp.peeksize().unwrap_or(0),
                     e
                 );
 
+                // Everything the peer sent was acknowledged before its FIN or SHUTDOWN
+                if self.peer_closed || self.peer_write_closed {
+                    return Ok(0).into();
+                }
+
                 if let Some(waker) = self.pending_receiver.replace(cx.waker().clone()) {
                     if !cx.waker().will_wake(&waker) {
                         waker.wake();
@@ -222,7 +1194,8 @@ impl KcpSocket {
             }
             Err(err) => Err(err).into(),
             Ok(n) => {
-                self.last_update = Instant::now();
+                self.last_update = self.clock.now();
+                self.on_message_received(n);
                 Ok(n).into()
             }
         }
@@ -233,223 +1206,3217 @@ impl KcpSocket {
         future::poll_fn(|cx| self.poll_recv(cx, buf)).await
     }
 
+    /// `recv` the next message into a buffer allocated with its exact size
+    ///
+    /// An empty `Bytes` is EOF, as `recv` returning `0`.
+    pub fn poll_recv_bytes(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Bytes>> {
+        let mut buf = vec![0u8; self.kcp.peeksize().unwrap_or(0)];
+        let n = ready!(self.poll_recv(cx, &mut buf))?;
+        buf.truncate(n);
+        Ok(Bytes::from(buf)).into()
+    }
+
+    /// `recv` the next message into a buffer allocated with its exact size
+    pub async fn recv_bytes(&mut self) -> KcpResult<Bytes> {
+        future::poll_fn(|cx| self.poll_recv_bytes(cx)).await
+    }
+
     pub fn flush(&mut self) -> KcpResult<()> {
+        self.update_pacing_rate();
         self.kcp.flush()?;
-        self.last_update = Instant::now();
+        self.estimate_cwnd();
+        self.oldest_unflushed = None;
+        self.last_update = self.clock.now();
         Ok(())
     }
 
-    fn try_wake_pending_waker(&mut self) -> bool {
-        let mut waked = false;
+    /// Tell the peer the current receive window right away
+    ///
+    /// KCP tells it once when reading reopens a full receive window. If that is lost, the peer only finds out by
+    /// probing, at least 7 seconds later. Call it after reading reopened the window to unstick the peer sooner.
+    pub fn announce_window(&mut self) -> KcpResult<()> {
+        if self.kcp.waiting_conv() {
+            return Ok(());
+        }
 
-        if self.pending_sender.is_some()
-            && self.kcp.wait_snd() < self.kcp.snd_wnd() as usize
-            && self.kcp.wait_snd() < self.kcp.rmt_wnd() as usize
-            && !self.kcp.waiting_conv()
-        {
-            let waker = self.pending_sender.take().unwrap();
-            waker.wake();
+        // KCP answers a window probe with its current window and `una` at the next flush, ask it as the peer would.
+        // The probe repeats what KCP knows of the peer, its window and a `una` acknowledging nothing new.
+        let mut probe = vec![0u8; kcp::KCP_OVERHEAD];
+        probe[..4].copy_from_slice(&self.kcp.conv().to_le_bytes());
+        probe[4] = KCP_CMD_WASK;
+        probe[6..8].copy_from_slice(&self.kcp.rmt_wnd().to_le_bytes());
+        probe[16..20].copy_from_slice(&self.peer_una.to_le_bytes());
+        self.kcp.input(&probe)?;
+        session_trace!(self, "[UPDATE] announcing window");
+        self.flush()
+    }
 
-            waked = true;
+    /// Wait until all sent data have been acknowledged by the peer
+    ///
+    /// Fails if the socket is closed or the link is dead before that.
+    pub fn poll_wait_acked(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        if let Some(err) = self.timed_out_error() {
+            return Err(err.into()).into();
+        }
+        if self.closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe).into()).into();
         }
 
-        if self.pending_receiver.is_some() {
-            if let Ok(peek) = self.kcp.peeksize() {
-                if peek > 0 {
-                    let waker = self.pending_receiver.take().unwrap();
-                    waker.wake();
-
-                    waked = true;
-                }
-            }
+        if self.kcp.wait_snd() == 0 {
+            return Ok(()).into();
         }
 
-        waked
-    }
+        if self.peer_closed {
+            return Err(io::Error::from(ErrorKind::BrokenPipe).into()).into();
+        }
 
-    pub fn update(&mut self) -> KcpResult<Instant> {
-        let now = now_millis();
-        self.kcp.update(now)?;
-        let next = self.kcp.check(now);
+        if self.kcp.is_dead_link() {
+            return Err(io::Error::new(ErrorKind::TimedOut, "dead link").into()).into();
+        }
 
-        self.try_wake_pending_waker();
+        if let Some(waker) = self.pending_acked.replace(cx.waker().clone()) {
+            if !cx.waker().will_wake(&waker) {
+                waker.wake();
+            }
+        }
 
-        Ok(Instant::now() + Duration::from_millis(next as u64))
+        Poll::Pending
     }
 
-    pub fn close(&mut self) {
-        self.closed = true;
-        if let Some(w) = self.pending_sender.take() {
-            w.wake();
+    /// Wait until all sent data have been acknowledged and the delayed output queue is empty
+    ///
+    /// Everything has left the UDP socket once it resolves. Fails as `poll_wait_acked`, or with `write_timeout`
+    /// if the queue stays blocked.
+    pub fn poll_flush_all(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        ready!(self.poll_wait_acked(cx))?;
+
+        if self.output_stats.delay_queued_bytes.load(Ordering::Relaxed) == 0 {
+            return Ok(()).into();
         }
-        if let Some(w) = self.pending_receiver.take() {
-            w.wake();
+        self.output_stats.queue_empty.register(cx.waker());
+        // Emptied before being registered
+        if self.output_stats.delay_queued_bytes.load(Ordering::Relaxed) == 0 {
+            return Ok(()).into();
         }
+
+        Poll::Pending
+    }
+
+    /// Count PUSH segments that have been received before, and measure reordering of the others
+    fn record_duplicate_segments(&mut self, buf: &[u8]) {
+        let rcv_wnd = self.kcp.rcv_wnd() as u32;
+        walk_segments(buf, |header| {
+            if header[4] != KCP_CMD_PUSH {
+                return;
+            }
+            self.segments_received += 1;
+
+            let ts = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+            let sn = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+            let offset = sn.wrapping_sub(self.recv_next_sn);
+            if (offset as i32) < 0 {
+                self.duplicate_segments += 1;
+            } else if offset == 0 {
+                // Segments sent no earlier than this one but received before it.
+                // Retransmissions are sent later than those, so losses aren't taken as reordering.
+                let overtaken = self
+                    .recv_ahead_sn
+                    .values()
+                    .filter(|&&ahead_ts| ahead_ts.wrapping_sub(ts) as i32 >= 0)
+                    .count() as u32;
+                self.max_reorder_distance = self.max_reorder_distance.max(overtaken);
+
+                self.recv_next_sn = self.recv_next_sn.wrapping_add(1);
+                while self.recv_ahead_sn.remove(&self.recv_next_sn).is_some() {
+                    self.recv_next_sn = self.recv_next_sn.wrapping_add(1);
+                }
+            } else if offset < rcv_wnd && self.recv_ahead_sn.insert(sn, ts).is_some() {
+                // Segments beyond the receive window are dropped by KCP, they are neither tracked
+                self.duplicate_segments += 1;
+            }
+        });
+    }
+
+    /// Apply the fast resend threshold, raised above the observed reorder distance if adaptive
+    fn apply_fast_resend(&mut self) {
+        let resend = self.nodelay.resend.max(0) as u32;
+        let fast_resend = if self.adaptive_fast_resend && resend > 0 {
+            resend.max(self.max_reorder_distance + 1)
+        } else {
+            resend
+        };
+
+        if fast_resend != self.fast_resend {
+            session_trace!(
+                self,
+                "[UPDATE] fast resend threshold {} -> {}",
+                self.fast_resend,
+                fast_resend
+            );
+            self.kcp.set_fast_resend(fast_resend);
+            self.fast_resend = fast_resend;
+        }
+    }
+
+    /// Set the number of skipping ACKs that triggers a fast retransmit, `0` disables fast retransmit
+    ///
+    /// Replaced by the next `swap_nodelay_config`.
+    pub fn set_fast_resend(&mut self, threshold: u32) {
+        self.nodelay.resend = threshold as i32;
+        if let Some(ref mut pending) = self.pending_nodelay {
+            pending.resend = threshold as i32;
+        }
+        self.apply_fast_resend();
+    }
+
+    /// Change the window sizes of a live session
+    ///
+    /// Segments already queued are kept. Shrinking the send window below `wait_snd` is allowed,
+    /// `send` stalls until pending segments drop below it. KCP raises a receive window below 128 to 128.
+    pub fn set_wndsize(&mut self, snd_wnd: u16, rcv_wnd: u16) {
+        self.kcp.set_wndsize(snd_wnd, rcv_wnd);
+        self.try_wake_pending_waker();
+    }
+
+    /// Change the MTU of a live session, e.g. reduced after the path dropped large datagrams
+    ///
+    /// Only segments created afterwards are sized by it.
+    pub fn set_mtu(&mut self, mtu: usize) -> KcpResult<()> {
+        check_mtu(mtu)?;
+        self.kcp.set_mtu(mtu)
+    }
+
+    /// MTU from `KcpConfig::mtu`
+    pub fn configured_mtu(&self) -> usize {
+        self.configured_mtu
+    }
+
+    /// MTU in effect
+    pub fn effective_mtu(&self) -> usize {
+        self.kcp.mtu()
+    }
+
+    pub fn snd_wnd(&self) -> u16 {
+        self.kcp.snd_wnd()
+    }
+
+    pub fn rcv_wnd(&self) -> u16 {
+        self.kcp.rcv_wnd()
+    }
+
+    /// Fast resend threshold in effect
+    pub fn fast_resend(&self) -> u32 {
+        self.fast_resend
+    }
+
+    /// Most segments observed overtaking an earlier sent segment from the peer
+    pub fn max_reorder_distance(&self) -> u32 {
+        self.max_reorder_distance
+    }
+
+    /// Every successful `recv` returns exactly one message unless KCP is running in stream mode
+    fn on_message_received(&mut self, n: usize) {
+        self.bytes_received += n as u64;
+        if !self.kcp.is_stream() {
+            self.messages_received += 1;
+        }
+    }
+
+    /// Forget messages that are no longer pending
+    ///
+    /// Segments are assumed to be acknowledged in order, which holds unless the peer selectively ACKs.
+    fn trim_pending_messages(&mut self) {
+        let wait_snd = self.kcp.wait_snd();
+        while let Some(&segments) = self.pending_message_segments.front() {
+            if self.pending_message_segments_total - segments < wait_snd {
+                break;
+            }
+            self.pending_message_segments.pop_front();
+            self.pending_message_segments_total -= segments;
+        }
+    }
+
+    fn try_wake_pending_waker(&mut self) -> bool {
+        let mut waked = false;
+
+        self.trim_pending_messages();
+
+        if self.pending_sender.is_some()
+            && self.kcp.wait_snd() < self.kcp.snd_wnd() as usize
+            && self.kcp.wait_snd() < self.kcp.rmt_wnd() as usize
+            && !self.kcp.waiting_conv()
+        {
+            let waker = self.pending_sender.take().unwrap();
+            waker.wake();
+
+            waked = true;
+        }
+
+        if self.pending_receiver.is_some() {
+            if let Ok(peek) = self.kcp.peeksize() {
+                if peek > 0 {
+                    let waker = self.pending_receiver.take().unwrap();
+                    waker.wake();
+
+                    waked = true;
+                }
+            }
+        }
+
+        if self.pending_acked.is_some() && (self.kcp.wait_snd() == 0 || self.kcp.is_dead_link()) {
+            let waker = self.pending_acked.take().unwrap();
+            waker.wake();
+
+            waked = true;
+        }
+
+        waked
+    }
+
+    pub fn update(&mut self) -> KcpResult<Instant> {
+        // Swap at the tick boundary, never in the middle of a flush
+        if let Some(nodelay) = self.pending_nodelay.take() {
+            let effective = KcpNoDelayConfig {
+                nc: nodelay.nc || self.no_congestion_control,
+                ..nodelay
+            };
+            effective.apply_config(&mut self.kcp);
+            self.nodelay = nodelay;
+            self.fast_resend = nodelay.resend.max(0) as u32;
+        }
+        self.apply_fast_resend();
+        self.update_pacing_rate();
+        self.send_queued_classes()?;
+
+        let datagrams = self.output_stats.datagrams.load(Ordering::Relaxed);
+
+        let now = self.clock.now_millis();
+        self.kcp.update(now)?;
+
+        // Data waited longer than the latency budget, don't wait for the next interval
+        if matches!(self.flush_deadline(), Some(deadline) if deadline <= self.clock.now()) {
+            session_trace!(self, "[UPDATE] max buffer latency exceeded, flushing");
+            self.kcp.flush()?;
+        }
+        self.estimate_cwnd();
+
+        let next = self.kcp.check(now);
+
+        self.last_update_flushed = self.output_stats.datagrams.load(Ordering::Relaxed) != datagrams;
+        if self.last_update_flushed {
+            self.oldest_unflushed = None;
+        }
+        self.record_memory_usage();
+        self.keepalive();
+        self.probe_mtu();
+        self.check_write_timeout();
+        self.check_dead_link();
+        self.send_shutdown();
+        self.send_fin();
+        self.check_connect_timeout();
+
+        self.try_wake_pending_waker();
+
+        let next = self.clock.now() + Duration::from_millis(next as u64);
+        Ok(self.flush_deadline().map_or(next, |deadline| next.min(deadline)))
+    }
+
+    /// Check if the delayed output queue has been stuck for `write_timeout`
+    fn check_write_timeout(&mut self) {
+        let timeout = match self.write_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        let queued = self.output_stats.delay_queued_bytes.load(Ordering::Relaxed);
+        let sent = self.output_stats.delayed_sent.load(Ordering::Relaxed);
+        if queued == 0 || sent != self.output_progress.1 {
+            self.output_progress = (self.clock.now(), sent);
+            return;
+        }
+
+        if !self.write_timed_out && self.elapsed(self.output_progress.0) > timeout {
+            error!(
+                "[UPDATE] {} bytes waited for the UDP socket longer than {:?}",
+                queued, timeout
+            );
+            self.write_timed_out = true;
+            if let Some(w) = self.pending_sender.take() {
+                w.wake();
+            }
+            self.output_stats.queue_empty.wake();
+        }
+    }
+
+    /// Check if nothing has been received from the peer within `connect_timeout`
+    fn check_connect_timeout(&mut self) {
+        let timeout = match self.connect_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+
+        if self.connected || self.connect_timed_out || self.elapsed(self.created) <= timeout {
+            return;
+        }
+
+        error!(
+            "[UPDATE] nothing received from {} within {:?}",
+            self.peer_addr(),
+            timeout
+        );
+        self.connect_timed_out = true;
+        if let Some(w) = self.pending_sender.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_receiver.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_acked.take() {
+            w.wake();
+        }
+        self.output_stats.queue_empty.wake();
+    }
+
+    /// Check if a segment has been sent `dead_link` times without being acknowledged
+    fn check_dead_link(&mut self) {
+        if !self.close_on_dead_link || self.dead || !self.kcp.is_dead_link() {
+            return;
+        }
+
+        error!("[UPDATE] dead link to {}", self.peer_addr());
+        self.dead = true;
+        if let Some(w) = self.pending_sender.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_receiver.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_acked.take() {
+            w.wake();
+        }
+        self.output_stats.queue_empty.wake();
+    }
+
+    /// Check if a segment was sent `KcpConfig::dead_link` times without being acknowledged
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// Check if a segment has been sent KCP's dead link limit of times, whether or not `KcpConfig::dead_link` is set
+    pub fn is_dead_link(&self) -> bool {
+        self.kcp.is_dead_link()
+    }
+
+    /// Current limit of bytes sent per second, the lower of `KcpConfig::send_rate_limit` and the bandwidth pool's share
+    pub fn send_rate_limit(&self) -> Option<u64> {
+        let share = self.bandwidth_share.as_ref().map(|share| &**share.rate());
+        limited_rate(self.send_rate_limit, share)
+    }
+
+    /// Weight of this session's share of `KcpConfig::bandwidth_pool`, default is 1
+    pub fn set_bandwidth_weight(&self, weight: u32) {
+        if let Some(ref share) = self.bandwidth_share {
+            share.set_weight(weight);
+        }
+    }
+
+    /// Pace datagrams at a window per smoothed RTT, the rate KCP sends at when its window is full
+    ///
+    /// KCP doesn't expose its congestion window, the send and remote windows bound it.
+    fn update_pacing_rate(&self) {
+        if let Some(ref pacing_rate) = self.pacing_rate {
+            let rate = if self.cwnd_pacing {
+                self.cwnd_rate()
+            } else {
+                self.window_rate().map(|(rate, _)| rate)
+            };
+            if let Some(rate) = rate {
+                pacing_rate.store(rate, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// A congestion window of segments per RTT, `None` before the first RTT sample
+    fn cwnd_rate(&self) -> Option<u64> {
+        let srtt = self.rtt.srtt?.max(1) as u64;
+        let wnd = self
+            .cwnd_estimate
+            .unwrap_or_else(|| self.kcp.snd_wnd().min(self.kcp.rmt_wnd()) as usize)
+            .max(1);
+        Some(wnd as u64 * self.kcp.mss() as u64 * 1000 / srtt)
+    }
+
+    /// Take the segments in flight after a flush as KCP's congestion window, if more are waiting for it
+    fn estimate_cwnd(&mut self) {
+        if !self.cwnd_pacing {
+            return;
+        }
+        let latest_sn = match *self.output_stats.latest_sn.lock() {
+            Some(sn) => sn,
+            None => return,
+        };
+        let in_flight = latest_sn.wrapping_add(1).wrapping_sub(self.peer_una) as usize;
+        if self.kcp.wait_snd() > in_flight {
+            self.cwnd_estimate = Some(in_flight);
+        }
+    }
+
+    /// Current pacing rate in bytes per second, `None` unless `KcpConfig::pacing` or before the first RTT sample
+    pub fn pacing_rate(&self) -> Option<u64> {
+        self.pacing_rate
+            .as_ref()
+            .map(|rate| rate.load(Ordering::Relaxed))
+            .filter(|&rate| rate > 0)
+    }
+
+    /// Check if nothing was received from the peer within `KcpConfig::connect_timeout`
+    pub fn is_connect_timed_out(&self) -> bool {
+        self.connect_timed_out
+    }
+
+    /// Error of a session failed by a timeout
+    fn timed_out_error(&self) -> Option<io::Error> {
+        if self.connect_timed_out {
+            Some(io::Error::new(ErrorKind::TimedOut, "connect timed out"))
+        } else if self.dead {
+            Some(io::Error::new(ErrorKind::TimedOut, "dead link"))
+        } else if self.write_timed_out {
+            Some(io::Error::new(ErrorKind::TimedOut, "write timed out"))
+        } else {
+            None
+        }
+    }
+
+    /// Check if packets waited for the UDP socket longer than `KcpConfig::write_timeout`
+    pub fn is_write_timed_out(&self) -> bool {
+        self.write_timed_out
+    }
+
+    /// Send a keep-alive if nothing has been sent for `keepalive_interval`
+    ///
+    /// It is a WINS segment without payload, absorbed by the peer's KCP, never surfaced to `recv`.
+    fn keepalive(&mut self) {
+        let interval = match self.keepalive_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        let datagrams = self.output_stats.datagrams.load(Ordering::Relaxed);
+        if datagrams != self.last_output.1 {
+            self.last_output = (self.clock.now(), datagrams);
+            return;
+        }
+
+        if self.elapsed(self.last_output.0) < interval || self.kcp.waiting_conv() {
+            return;
+        }
+
+        let packet = self.control_segment(KCP_CMD_WINS, KEEPALIVE_FRG, kcp::KCP_OVERHEAD);
+        let target_addr = self.peer_addr();
+        match self.send_packet(packet, target_addr) {
+            Ok(..) => session_trace!(self, "[UPDATE] sent keep-alive to {}", target_addr),
+            Err(err) => session_trace!(self, "[UPDATE] keep-alive to {} failed, error: {}", target_addr, err),
+        }
+        self.last_output.0 = self.clock.now();
+    }
+
+    /// Build a segment without data for the peer's KCP, padded to `size`
+    fn control_segment(&self, cmd: u8, frg: u8, size: usize) -> Vec<u8> {
+        // conv(4) cmd(1) frg(1) wnd(2) ts(4) sn(4) una(4) len(4)
+        // Every segment carries wnd and una, the peer trusts them as much as KCP's. KCP doesn't expose them, repeat
+        // the ones it last advertised, nothing is received and queued before it wrote any.
+        let (wnd, una) = self.output_stats.advertised.lock().unwrap_or((self.kcp.rcv_wnd(), 0));
+        let mut packet = vec![0u8; size.max(kcp::KCP_OVERHEAD)];
+        packet[..4].copy_from_slice(&self.kcp.conv().to_le_bytes());
+        packet[4] = cmd;
+        packet[5] = frg;
+        packet[6..8].copy_from_slice(&wnd.to_le_bytes());
+        packet[8..12].copy_from_slice(&self.clock.now_millis().to_le_bytes());
+        packet[16..20].copy_from_slice(&una.to_le_bytes());
+        let len = (packet.len() - kcp::KCP_OVERHEAD) as u32;
+        packet[20..24].copy_from_slice(&len.to_le_bytes());
+        packet
+    }
+
+    /// Send a packet not written by KCP, through the packet transform
+    fn send_packet(&self, packet: Vec<u8>, target_addr: SocketAddr) -> io::Result<usize> {
+        let packet = match self.packet_transform.as_deref() {
+            Some(transform) => encode_packet(transform, packet),
+            None => packet,
+        };
+        self.socket.try_send_to(&packet, target_addr)
+    }
+
+    /// Send a probe for a larger MTU if it is time to
+    ///
+    /// The probe is a marked WINS padded to the size probed, KCP skips its payload and the peer replies with another
+    /// marked WINS.
+    fn probe_mtu(&mut self) {
+        // The peer replies at its next flush
+        let timeout = Duration::from_millis(self.rtt.rto(&self.nodelay) as u64 * 2);
+        let mtu = self.kcp.mtu();
+        let waiting_conv = self.kcp.waiting_conv();
+        let now = self.clock.now();
+
+        let prober = match self.mtu_prober {
+            Some(ref mut prober) => prober,
+            None => return,
+        };
+
+        if let Some((size, sent_time)) = prober.in_flight {
+            if now.saturating_duration_since(sent_time) < timeout {
+                return;
+            }
+            session_trace!(self, "[MTU] probe of {} bytes timed out", size);
+            let prober = self.mtu_prober.as_mut().unwrap();
+            prober.in_flight = None;
+            prober.ceiling = Some(prober.ceiling.map_or(size, |ceiling| ceiling.min(size)));
+            return;
+        }
+
+        if now.saturating_duration_since(prober.last_probe) < prober.config.interval || waiting_conv {
+            return;
+        }
+        prober.last_probe = now;
+
+        let size = match prober.next_size(mtu) {
+            Some(size) => size,
+            None => return,
+        };
+
+        let mut packet = self.control_segment(KCP_CMD_WINS, MTU_PROBE_FRG, size);
+        packet[12..16].copy_from_slice(&(size as u32).to_le_bytes());
+        match self.send_packet(packet, self.peer_addr()) {
+            Ok(..) => {
+                session_trace!(self, "[MTU] probing {} bytes, current mtu: {}", size, mtu);
+                if let Some(ref mut prober) = self.mtu_prober {
+                    prober.in_flight = Some((size, self.clock.now()));
+                }
+            }
+            Err(err) => session_trace!(self, "[MTU] probe of {} bytes failed, error: {}", size, err),
+        }
+    }
+
+    /// Raise MTU if `buf` answers the probe in flight
+    fn on_mtu_probe_reply(&mut self, buf: &[u8]) {
+        let size = match self.mtu_prober {
+            Some(MtuProber {
+                in_flight: Some((size, _)),
+                ..
+            }) => size,
+            _ => return,
+        };
+
+        // Only the reply to this probe, not KCP's WINS or a late reply of an earlier probe
+        let mut replied = false;
+        walk_segments(buf, |header| {
+            let sn = u32::from_le_bytes([header[12], header[13], header[14], header[15]]);
+            if header[4] == KCP_CMD_WINS && header[5] == MTU_PROBE_REPLY_FRG && sn == size as u32 {
+                replied = true;
+            }
+        });
+        if !replied {
+            return;
+        }
+
+        if let Some(ref mut prober) = self.mtu_prober {
+            prober.in_flight = None;
+        }
+        match self.kcp.set_mtu(size) {
+            Ok(..) => session_trace!(self, "[MTU] probe of {} bytes replied, mtu raised", size),
+            Err(err) => error!("[MTU] set mtu {} failed, error: {}", size, err),
+        }
+    }
+
+    /// Reply to the MTU probes in `buf`, whether probing is enabled here or not
+    fn reply_mtu_probe(&self, buf: &[u8]) {
+        let mut probed = None;
+        walk_segments(buf, |header| {
+            if header[4] == KCP_CMD_WINS && header[5] == MTU_PROBE_FRG {
+                probed = Some([header[12], header[13], header[14], header[15]]);
+            }
+        });
+        let probed = match probed {
+            Some(probed) => probed,
+            None => return,
+        };
+
+        let mut packet = self.control_segment(KCP_CMD_WINS, MTU_PROBE_REPLY_FRG, kcp::KCP_OVERHEAD);
+        packet[12..16].copy_from_slice(&probed);
+        if let Err(err) = self.send_packet(packet, self.peer_addr()) {
+            session_trace!(self, "[MTU] probe reply failed, error: {}", err);
+        }
+    }
+
+    /// When unflushed data exceeds `max_buffer_latency`
+    fn flush_deadline(&self) -> Option<Instant> {
+        match (self.oldest_unflushed, self.max_buffer_latency) {
+            (Some(since), Some(budget)) => Some(since + budget),
+            _ => None,
+        }
+    }
+
+    /// Estimated bytes buffered by this session
+    ///
+    /// KCP doesn't expose its buffers, so it is estimated from: segments waiting to be sent or acknowledged
+    /// (counted as full segments), the next message ready to be received, packets in the delayed output queue,
+    /// and messages of `send_with_class` waiting for the send window.
+    pub fn memory_usage(&self) -> usize {
+        let send = self.kcp.wait_snd() * self.kcp.mss();
+        let recv = self.kcp.peeksize().unwrap_or(0);
+        let output = self.output_stats.delay_queued_bytes.load(Ordering::Relaxed) as usize;
+        send + recv + output + self.class_queued_bytes
+    }
+
+    fn record_memory_usage(&mut self) {
+        self.peak_memory = self.peak_memory.max(self.memory_usage());
+    }
+
+    /// Peak of `memory_usage`, sampled whenever buffers may grow
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory
+    }
+
+    /// Check if `peak_memory` exceeded `KcpConfig::max_session_memory`
+    pub fn is_memory_exceeded(&self) -> bool {
+        matches!(self.max_memory, Some(max) if self.peak_memory > max)
+    }
+
+    /// Check if the last `update` emitted any datagram
+    pub fn last_update_flushed(&self) -> bool {
+        self.last_update_flushed
+    }
+
+    /// Close the socket, waking all pending tasks
+    ///
+    /// Sends a FIN to the peer unless it has closed first, after everything sent has been acknowledged. Until then
+    /// `update` keeps retransmitting and sends the FIN once the last ACK arrives. The FIN isn't retransmitted, if it
+    /// is lost the peer still learns from its idle timeout.
+    pub fn close(&mut self) {
+        if !self.closed && !self.peer_closed && !self.kcp.waiting_conv() {
+            self.fin_pending = true;
+        }
+
+        self.closed = true;
+        self.send_fin();
+        if let Some(w) = self.pending_sender.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_receiver.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_acked.take() {
+            w.wake();
+        }
+        self.output_stats.queue_empty.wake();
+    }
+
+    /// Shut down writing, the peer's `recv` returns EOF after everything sent so far
+    ///
+    /// Receiving goes on until the peer shuts down its writing or closes, sending fails with `BrokenPipe`.
+    pub fn shutdown_write(&mut self) -> KcpResult<()> {
+        if self.closed || self.write_closed {
+            return Ok(());
+        }
+
+        self.write_closed = true;
+        if let Some(w) = self.pending_sender.take() {
+            w.wake();
+        }
+        self.flush()?;
+        self.send_shutdown();
+        Ok(())
+    }
+
+    /// Send a SHUTDOWN once everything sent has been acknowledged, repeated every RTO
+    ///
+    /// Sent after the data so the peer has received all of it, and repeated since KCP doesn't retransmit it.
+    fn send_shutdown(&mut self) {
+        if !self.write_closed
+            || self.closed
+            || self.peer_closed
+            || self.kcp.waiting_conv()
+            || self.kcp.wait_snd() > 0
+            || self.class_queued_bytes > 0
+        {
+            return;
+        }
+        if matches!(self.shutdown_sent, Some(sent) if self.elapsed(sent) < self.rto()) {
+            return;
+        }
+
+        let packet = self.control_segment(KCP_CMD_WINS, SHUTDOWN_FRG, kcp::KCP_OVERHEAD);
+        let target_addr = self.peer_addr();
+        match self.send_packet(packet, target_addr) {
+            Ok(..) => session_trace!(self, "[SHUTDOWN] sent SHUTDOWN to {}", target_addr),
+            Err(err) => session_trace!(self, "[SHUTDOWN] SHUTDOWN to {} failed, error: {}", target_addr, err),
+        }
+        self.shutdown_sent = Some(self.clock.now());
+    }
+
+    /// Send the FIN of `close` once everything sent has been acknowledged
+    ///
+    /// Sent after the data, the peer drains everything before reading EOF. ACKs still pending go out first, the
+    /// peer waiting for them would take the FIN for a close with its data unacknowledged.
+    fn send_fin(&mut self) {
+        if !self.fin_pending || self.peer_closed || self.kcp.wait_snd() > 0 {
+            return;
+        }
+        if let Err(err) = self.kcp.flush() {
+            session_trace!(self, "[CLOSE] flush ACKs before FIN failed, error: {}", err);
+        }
+
+        let packet = self.control_segment(KCP_CMD_WINS, FIN_FRG, kcp::KCP_OVERHEAD);
+        let target_addr = self.peer_addr();
+        match self.send_packet(packet, target_addr) {
+            Ok(..) => session_trace!(self, "[CLOSE] sent FIN to {}", target_addr),
+            Err(err) => session_trace!(self, "[CLOSE] FIN to {} failed, error: {}", target_addr, err),
+        }
+        self.fin_pending = false;
+    }
+
+    /// Check if both peers shut down writing, and everything was acknowledged and received
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown_sent.is_some() && self.peer_write_closed && self.kcp.peeksize().is_err()
+    }
+
+    /// Peer closed, nothing more will be received or acknowledged
+    fn on_peer_closed(&mut self) {
+        self.peer_closed = true;
+        if let Some(w) = self.pending_sender.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_receiver.take() {
+            w.wake();
+        }
+        if let Some(w) = self.pending_acked.take() {
+            w.wake();
+        }
+        self.output_stats.queue_empty.wake();
+    }
+
+    /// Check if the peer has closed and everything it sent has been received
+    pub fn is_peer_closed(&self) -> bool {
+        self.peer_closed && self.kcp.peeksize().is_err()
+    }
+
+    /// Schedule `nodelay` to be applied at the start of the next `update`
+    ///
+    /// Returns the config it replaces, which is the previously scheduled one if it hasn't been applied yet.
+    pub fn swap_nodelay_config(&mut self, nodelay: KcpNoDelayConfig) -> KcpNoDelayConfig {
+        self.pending_nodelay.replace(nodelay).unwrap_or(self.nodelay)
+    }
+
+    pub fn nodelay_config(&self) -> KcpNoDelayConfig {
+        self.nodelay
+    }
+
+    pub fn set_log_level(&mut self, level: LevelFilter) {
+        self.log_level = level;
+    }
+
+    /// Send everything to `peer_addr` from now on, e.g. after the peer's NAT mapping changed
+    ///
+    /// Packets already waiting for the delayed sender still go to the previous address.
+    pub fn migrate_peer(&mut self, peer_addr: SocketAddr) {
+        let previous = std::mem::replace(&mut *self.target_addr.lock(), peer_addr);
+        session_trace!(self, "[SESSION] peer migrated from {} to {}", previous, peer_addr);
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        *self.target_addr.lock()
+    }
+
+    pub fn packet_transform(&self) -> Option<&Arc<dyn PacketTransform>> {
+        self.packet_transform.as_ref()
     }
 
     pub fn udp_socket(&self) -> &Arc<UdpSocket> {
         &self.socket
     }
 
-    pub fn can_close(&self) -> bool {
-        self.kcp.wait_snd() == 0
+    pub fn can_close(&self) -> bool {
+        self.kcp.wait_snd() == 0
+    }
+
+    pub fn conv(&self) -> u32 {
+        self.kcp.conv()
+    }
+
+    pub fn set_conv(&mut self, conv: u32) {
+        self.kcp.set_conv(conv);
+    }
+
+    pub fn waiting_conv(&self) -> bool {
+        self.kcp.waiting_conv()
+    }
+
+    /// Check if the session is still waiting for the server to allocate its conv, nothing can be sent before that
+    ///
+    /// Never the case for a session created with a conv.
+    pub fn is_handshaking(&self) -> bool {
+        self.waiting_conv()
+    }
+
+    /// Whether `buf` is a KCP datagram for this session
+    ///
+    /// Datagrams shorter than a KCP header are never owned. While waiting for its conv, every datagram is, and so
+    /// are those with the conv the peer gave up on a simultaneous open.
+    pub fn owns_datagram(&self, buf: &[u8]) -> bool {
+        if buf.len() < kcp::KCP_OVERHEAD {
+            return false;
+        }
+        let conv = kcp::get_conv(buf);
+        self.waiting_conv() || conv == self.conv() || self.abandoned_peer_conv == Some(conv)
+    }
+
+    /// Resolve both peers connecting to each other at once, each starting a session with its own conv
+    ///
+    /// Returns `true` if `buf` comes from the peer's session with another conv, before anything was received from
+    /// it. That is any datagram, the peer's first one may only acknowledge ours. Both peers keep the lower conv of
+    /// the two, this session switches to the peer's conv if it is lower, or if nothing has been sent yet so the peer
+    /// would never learn this session's conv. Also `true` for datagrams the peer sent with the conv it gave up,
+    /// `input` takes them as this session's.
+    pub fn resolve_simultaneous_open(&mut self, buf: &[u8]) -> bool {
+        let conv = self.kcp.conv();
+        let peer_conv = kcp::get_conv(buf);
+        if self.abandoned_peer_conv == Some(peer_conv) {
+            return true;
+        }
+        if self.connected || self.kcp.waiting_conv() || peer_conv == 0 || peer_conv == conv {
+            return false;
+        }
+
+        if peer_conv < conv || !self.sent_first {
+            session_trace!(
+                self,
+                "[CONV] simultaneous open, conv: {} switched to peer's conv: {}",
+                conv,
+                peer_conv
+            );
+            self.kcp.set_conv(peer_conv);
+        } else {
+            session_trace!(
+                self,
+                "[CONV] simultaneous open, conv: {} kept over peer's conv: {}",
+                conv,
+                peer_conv
+            );
+            self.abandoned_peer_conv = Some(peer_conv);
+        }
+        true
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.kcp.is_stream()
+    }
+
+    pub fn peek_size(&self) -> KcpResult<usize> {
+        self.kcp.peeksize()
+    }
+
+    pub fn messages_received(&self) -> u64 {
+        self.messages_received
+    }
+
+    pub fn udp_send_retry_count(&self) -> u64 {
+        self.output_stats.send_retries.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_send_would_block_count(&self) -> u64 {
+        self.output_stats.send_would_block.load(Ordering::Relaxed)
+    }
+
+    /// Average and maximum time packets waited in the delayed queue before being sent
+    pub fn udp_send_queue_delay(&self) -> (Duration, Duration) {
+        let stats = &self.output_stats;
+        let sent = stats.delayed_sent.load(Ordering::Relaxed);
+        let total_us = stats.delay_total_us.load(Ordering::Relaxed);
+        let avg_us = total_us.checked_div(sent).unwrap_or(0);
+        let max_us = stats.delay_max_us.load(Ordering::Relaxed);
+        (Duration::from_micros(avg_us), Duration::from_micros(max_us))
+    }
+
+    pub fn ack_flood_dropped(&self) -> u64 {
+        self.ack_flood_dropped
+    }
+
+    /// Number of PUSH segments received again after the first time
+    pub fn duplicate_segments(&self) -> u64 {
+        self.duplicate_segments
+    }
+
+    /// Number of keep-alive intervals passed since the last input, reset by any input
+    pub fn missed_keepalives(&self) -> u32 {
+        match self.keepalive_interval {
+            Some(interval) if interval.as_millis() > 0 => {
+                (self.elapsed(self.last_input).as_millis() / interval.as_millis()) as u32
+            }
+            _ => 0,
+        }
+    }
+
+    /// Most recent RTT sample in milliseconds, `None` before the first ACK
+    pub fn last_rtt_sample(&self) -> Option<u32> {
+        self.last_rtt_sample
+    }
+
+    /// Health of the session in `[0, 1]`, `1` is perfectly healthy
+    ///
+    /// Combines the weighted scores of:
+    ///
+    /// 1. Loss, ratio of retransmitted PUSH segments
+    /// 2. RTT trend, smoothed RTT over the last sample, dropping while RTT is rising
+    /// 3. Window stalls, ratio of sends blocked by a full window
+    ///
+    /// Loss and stalls are accounted since the session was created.
+    pub fn health_score(&self, weights: &HealthScoreWeights) -> f32 {
+        let segments = self.output_stats.push_segments.load(Ordering::Relaxed);
+        let retransmitted = self.output_stats.retransmitted_segments.load(Ordering::Relaxed);
+        let loss_score = if segments == 0 {
+            1.0
+        } else {
+            1.0 - retransmitted as f32 / segments as f32
+        };
+
+        let rtt_score = match (self.rtt.srtt, self.last_rtt_sample) {
+            (Some(srtt), Some(rtt)) if rtt > srtt => srtt as f32 / rtt as f32,
+            _ => 1.0,
+        };
+
+        let stall_score = if self.send_calls == 0 {
+            1.0
+        } else {
+            1.0 - self.send_stalls as f32 / self.send_calls as f32
+        };
+
+        let total = weights.loss + weights.rtt + weights.stall;
+        if total <= 0.0 {
+            return 1.0;
+        }
+        let score = (weights.loss * loss_score + weights.rtt * rtt_score + weights.stall * stall_score) / total;
+        score.clamp(0.0, 1.0)
+    }
+
+    /// Effective send rate limit in bytes per second and the mechanism imposing it
+    ///
+    /// The lowest of the limits in effect, windows limit it from the first RTT sample. `None` if nothing does yet.
+    pub fn current_send_rate_limit(&self) -> Option<(u64, RateLimitSource)> {
+        let share = self
+            .bandwidth_share
+            .as_ref()
+            .map(|share| share.rate().load(Ordering::Relaxed))
+            .filter(|&share| share > 0);
+        // Pacing goes first, it spaces datagrams at the window rate it ties with
+        let limits = [
+            self.pacing_rate().map(|rate| (rate, RateLimitSource::Pacing)),
+            self.window_rate(),
+            self.max_pps
+                .map(|max_pps| (max_pps as u64 * self.kcp.mtu() as u64, RateLimitSource::MaxPps)),
+            self.send_rate_limit.map(|rate| (rate, RateLimitSource::SendRateLimit)),
+            share.map(|share| (share, RateLimitSource::BandwidthPool)),
+        ];
+        limits.iter().flatten().min_by_key(|(rate, _)| *rate).copied()
+    }
+
+    /// At most a window of segments is sent per RTT, `None` before the first RTT sample
+    fn window_rate(&self) -> Option<(u64, RateLimitSource)> {
+        let srtt = self.rtt.srtt?.max(1) as u64;
+        let (wnd, source) = if self.kcp.rmt_wnd() < self.kcp.snd_wnd() {
+            (self.kcp.rmt_wnd(), RateLimitSource::RemoteWindow)
+        } else {
+            (self.kcp.snd_wnd(), RateLimitSource::SendWindow)
+        };
+        let rate = wnd as u64 * self.kcp.mss() as u64 * 1000 / srtt;
+        Some((rate, source))
+    }
+
+    /// Parameters in effect, including the ones changed at runtime
+    ///
+    /// A `swap_nodelay_config` is in effect after the next `update`.
+    pub fn effective_config(&self) -> EffectiveConfig {
+        EffectiveConfig {
+            mtu: self.kcp.mtu(),
+            mss: self.kcp.mss(),
+            snd_wnd: self.kcp.snd_wnd(),
+            rcv_wnd: self.kcp.rcv_wnd(),
+            interval: self.nodelay.interval.clamp(10, 5000),
+            nodelay: self.nodelay.nodelay,
+            resend: self.fast_resend,
+            nc: self.nodelay.nc || self.no_congestion_control,
+            min_rto: RttEstimator::min_rto(&self.nodelay),
+        }
+    }
+
+    /// Snapshot of the transmission state
+    ///
+    /// RTT and RTO are approximations kept outside KCP from the ACKs received, KCP doesn't expose its own.
+    pub fn stats(&self) -> KcpStats {
+        KcpStats {
+            srtt: self.rtt.srtt.map(|srtt| Duration::from_millis(srtt as u64)),
+            rto: self.rto(),
+            wait_snd: self.kcp.wait_snd(),
+            segments_sent: self.output_stats.push_segments.load(Ordering::Relaxed),
+            segments_received: self.segments_received,
+            segments_retransmitted: self.output_stats.retransmitted_segments.load(Ordering::Relaxed),
+            segment_sizes: self
+                .output_stats
+                .segment_sizes
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// Bytes accepted by `send`
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Bytes returned by `recv`, or pushed to `on_data`
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Metrics of this session in Prometheus text exposition format, labeled by conv and peer
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_metrics(&self) -> String {
+        crate::prometheus::render(&[crate::prometheus::Sample::new(self)])
+    }
+
+    /// Summary of the whole session, for reporting it is closing for `reason`
+    pub fn summary(&self, reason: CloseReason) -> SessionSummary {
+        SessionSummary {
+            conv: self.kcp.conv(),
+            duration: self.elapsed(self.created),
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            segments_retransmitted: self.output_stats.retransmitted_segments.load(Ordering::Relaxed),
+            max_rtt: self.max_rtt_sample.map(|rtt| Duration::from_millis(rtt as u64)),
+            reason,
+        }
+    }
+
+    /// Smoothed RTT estimated outside KCP, `Duration::ZERO` before the first ACK
+    pub fn rtt(&self) -> Duration {
+        Duration::from_millis(self.rtt.srtt.unwrap_or(0) as u64)
+    }
+
+    /// Retransmission timeout approximated from the RTT estimate, not read from KCP
+    pub fn rto(&self) -> Duration {
+        Duration::from_millis(self.rtt.rto(&self.nodelay) as u64)
+    }
+
+    /// Time since an ACK last acknowledged pending segments, `0` if nothing is pending
+    pub fn since_last_ack(&self) -> Duration {
+        if self.kcp.wait_snd() == 0 {
+            Duration::ZERO
+        } else {
+            self.elapsed(self.last_ack_progress)
+        }
+    }
+
+    /// Number of messages sent but not yet fully acknowledged, `0` in stream mode
+    pub fn pending_messages(&self) -> usize {
+        let wait_snd = self.kcp.wait_snd();
+        let mut segments = 0;
+        self.pending_message_segments
+            .iter()
+            .rev()
+            .take_while(|&&s| {
+                let pending = segments < wait_snd;
+                segments += s;
+                pending
+            })
+            .count()
+    }
+
+    pub fn last_update_time(&self) -> Instant {
+        self.last_update
+    }
+
+    /// Time since the last update, which expires idle sessions, not counting time while frozen
+    pub fn idle_time(&self) -> Duration {
+        let now = self.idle_frozen.unwrap_or_else(|| self.clock.now());
+        now.saturating_duration_since(self.last_update)
+    }
+
+    /// Stop `idle_time` from growing, without sending anything
+    pub fn freeze_idle_timer(&mut self) {
+        if self.idle_frozen.is_none() {
+            self.idle_frozen = Some(self.clock.now());
+        }
+    }
+
+    /// Let `idle_time` grow again from where it was frozen
+    pub fn unfreeze_idle_timer(&mut self) {
+        if self.idle_frozen.is_some() {
+            let idle = self.idle_time();
+            self.idle_frozen = None;
+            self.last_update = self.clock.now().checked_sub(idle).unwrap_or(self.last_update);
+        }
+    }
+
+    /// Pending packets filled up the local send window or the remote receive window
+    pub fn is_window_stalled(&self) -> bool {
+        self.kcp.wait_snd() >= self.kcp.snd_wnd() as usize || self.kcp.wait_snd() >= self.kcp.rmt_wnd() as usize
+    }
+
+    /// Bytes that can be sent without overflowing the send window or the remote receive window
+    ///
+    /// At least one segment, at most as many segments as KCP accepts in one `send`.
+    pub fn send_window_available(&self) -> usize {
+        let wnd = self.kcp.snd_wnd().min(self.kcp.rmt_wnd()) as usize;
+        let segments = wnd.saturating_sub(self.kcp.wait_snd()).clamp(1, MAX_SEND_SEGMENTS);
+        segments * self.kcp.mss()
+    }
+
+    pub fn need_flush(&self) -> bool {
+        self.is_window_stalled() && !self.kcp.waiting_conv()
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use futures::task::noop_waker_ref;
+    use kcp::{Error as KcpError, Kcp};
+    use log::{trace, LevelFilter};
+    use std::{
+        cell::Cell,
+        io::{self, Write},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc, Mutex as StdMutex,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::{
+        net::UdpSocket,
+        sync::Mutex,
+        time::{self, Instant},
+    };
+
+    use spin::Mutex as SpinMutex;
+
+    use super::{KcpSocket, OutputLimiter, RttEstimator, UdpOutput};
+    use crate::{
+        clock::{Clock, SystemClock, TestClock},
+        config::{HealthScoreWeights, KcpConfig, KcpNoDelayConfig, MtuProbeConfig},
+        error::SendWindowFull,
+        priority::{SendClass, SendQueueOrder},
+        rate::RateLimitSource,
+        transform::PacketTransform,
+        utils::now_millis,
+    };
+
+    thread_local! {
+        /// `session_trace!` messages let through on this thread
+        pub(super) static SESSION_TRACES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// KCP's congestion window is 0 until the end of its first flush, nothing would be sent by it otherwise
+    fn first_flush_config() -> KcpConfig {
+        KcpConfig {
+            no_congestion_control: true,
+            ..Default::default()
+        }
+    }
+
+    /// KCP segment without payload, as sent by a peer
+    fn segment(conv: u32, cmd: u8, wnd: u16, ts: u32, sn: u32, una: u32) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(kcp::KCP_OVERHEAD);
+        packet.extend_from_slice(&conv.to_le_bytes());
+        packet.extend_from_slice(&[cmd, 0]); // frg
+        packet.extend_from_slice(&wnd.to_le_bytes());
+        packet.extend_from_slice(&ts.to_le_bytes());
+        packet.extend_from_slice(&sn.to_le_bytes());
+        packet.extend_from_slice(&una.to_le_bytes());
+        packet.extend_from_slice(&0u32.to_le_bytes()); // len
+        packet
+    }
+
+    /// PUSH segment carrying `data`
+    fn push_segment(conv: u32, ts: u32, sn: u32, data: &[u8]) -> Vec<u8> {
+        let mut packet = segment(conv, super::KCP_CMD_PUSH, 256, ts, sn, 0);
+        packet[kcp::KCP_OVERHEAD - 4..].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    /// XOR every byte with a key
+    #[derive(Debug)]
+    struct XorTransform(u8);
+
+    impl PacketTransform for XorTransform {
+        fn encode(&self, buf: &mut Vec<u8>) {
+            buf.iter_mut().for_each(|b| *b ^= self.0);
+        }
+
+        fn decode(&self, buf: &mut [u8]) -> usize {
+            buf.iter_mut().for_each(|b| *b ^= self.0);
+            buf.len()
+        }
+    }
+
+    /// Output recording every datagram KCP emits
+    #[derive(Clone, Default)]
+    struct RecordOutput(Arc<StdMutex<Vec<Vec<u8>>>>);
+
+    impl RecordOutput {
+        fn take(&self) -> Vec<Vec<u8>> {
+            std::mem::take(&mut *self.0.lock().unwrap())
+        }
+    }
+
+    impl Write for RecordOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().push(buf.to_owned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn kcp_echo() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        // s1 connects s2
+        let s1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let s1_addr = s1.local_addr().unwrap();
+        let s2_addr = s2.local_addr().unwrap();
+
+        let s1 = Arc::new(s1);
+        let s2 = Arc::new(s2);
+
+        let config = KcpConfig::default();
+        let kcp1 = KcpSocket::new(&config, 0, s1.clone(), s2_addr, true).unwrap();
+        let kcp2 = KcpSocket::new(&config, CONV, s2.clone(), s1_addr, true).unwrap();
+
+        let kcp1 = Arc::new(Mutex::new(kcp1));
+        let kcp2 = Arc::new(Mutex::new(kcp2));
+
+        let kcp1_task = {
+            let kcp1 = kcp1.clone();
+            tokio::spawn(async move {
+                loop {
+                    let mut kcp = kcp1.lock().await;
+                    let next = kcp.update().expect("update");
+                    trace!("kcp1 next tick {:?}", next);
+                    time::sleep_until(Instant::from_std(next)).await;
+                }
+            })
+        };
+
+        let kcp2_task = {
+            let kcp2 = kcp2.clone();
+            tokio::spawn(async move {
+                loop {
+                    let mut kcp = kcp2.lock().await;
+                    let next = kcp.update().expect("update");
+                    trace!("kcp2 next tick {:?}", next);
+                    time::sleep_until(Instant::from_std(next)).await;
+                }
+            })
+        };
+
+        const SEND_BUFFER: &[u8] = b"HELLO WORLD";
+
+        {
+            let n = kcp1.lock().await.send(SEND_BUFFER).await.unwrap();
+            assert_eq!(n, SEND_BUFFER.len());
+        }
+
+        let echo_task = tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+
+            loop {
+                let n = s2.recv(&mut buf).await.unwrap();
+
+                let packet = &mut buf[..n];
+
+                let conv = kcp::get_conv(packet);
+                if conv == 0 {
+                    kcp::set_conv(packet, CONV);
+                }
+
+                let mut kcp2 = kcp2.lock().await;
+                kcp2.input(packet).unwrap();
+
+                match kcp2.try_recv(&mut buf) {
+                    Ok(n) => {
+                        let received = &buf[..n];
+                        kcp2.send(received).await.unwrap();
+                    }
+                    Err(KcpError::RecvQueueEmpty) => {
+                        continue;
+                    }
+                    Err(err) => {
+                        panic!("kcp.recv error: {:?}", err);
+                    }
+                }
+            }
+        });
+
+        {
+            let mut buf = [0u8; 1024];
+
+            loop {
+                let n = s1.recv(&mut buf).await.unwrap();
+
+                let packet = &buf[..n];
+
+                let mut kcp1 = kcp1.lock().await;
+                kcp1.input(packet).unwrap();
+
+                match kcp1.try_recv(&mut buf) {
+                    Ok(n) => {
+                        let received = &buf[..n];
+                        assert_eq!(received, SEND_BUFFER);
+                        break;
+                    }
+                    Err(KcpError::RecvQueueEmpty) => {
+                        continue;
+                    }
+                    Err(err) => {
+                        panic!("kcp.recv error: {:?}", err);
+                    }
+                }
+            }
+        }
+
+        echo_task.abort();
+        kcp1_task.abort();
+        kcp2_task.abort();
+    }
+
+    #[tokio::test]
+    async fn ack_flood_dropped() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            wnd_size: (8, 256),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+
+        let acks = |count: u32| {
+            (0..count)
+                .flat_map(|sn| segment(CONV, super::KCP_CMD_ACK, 256, now_millis(), sn, 0))
+                .collect::<Vec<_>>()
+        };
+
+        // As many ACKs as a datagram of the MTU holds, however small the send window is
+        let full = (config.mtu / kcp::KCP_OVERHEAD) as u32;
+        kcp.input(&acks(full)).unwrap();
+        assert_eq!(kcp.ack_flood_dropped(), 0);
+
+        assert!(!kcp.input(&acks(1000)).unwrap());
+        assert_eq!(kcp.ack_flood_dropped(), 1);
+    }
+
+    #[test]
+    fn kcp_emitted_bytes() {
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let output = RecordOutput::default();
+        let mut kcp = Kcp::new(0x01020304, output.clone());
+        config.apply_config(&mut kcp).unwrap();
+
+        kcp.send(b"HELLO").unwrap();
+        kcp.update(0).unwrap();
+
+        #[rustfmt::skip]
+        const EXPECTED: &[u8] = &[
+            0x04, 0x03, 0x02, 0x01, // conv
+            81,                     // cmd: PUSH
+            0,                      // frg
+            0x00, 0x01,             // wnd: 256
+            0x00, 0x00, 0x00, 0x00, // ts
+            0x00, 0x00, 0x00, 0x00, // sn
+            0x00, 0x00, 0x00, 0x00, // una
+            0x05, 0x00, 0x00, 0x00, // len
+            b'H', b'E', b'L', b'L', b'O',
+        ];
+        assert_eq!(output.take(), vec![EXPECTED.to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn swap_nodelay_config() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        let previous = kcp.swap_nodelay_config(KcpNoDelayConfig::fastest());
+        assert_eq!(previous.interval, config.nodelay.interval);
+        assert_eq!(kcp.nodelay_config().interval, config.nodelay.interval);
+
+        // The next update is scheduled by the new interval
+        let next = kcp.update().unwrap();
+        assert!(next <= std::time::Instant::now() + Duration::from_millis(KcpNoDelayConfig::fastest().interval as u64));
+        assert_eq!(kcp.nodelay_config().interval, KcpNoDelayConfig::fastest().interval);
+        assert_eq!(kcp.nodelay_config().resend, KcpNoDelayConfig::fastest().resend);
+
+        let effective = kcp.effective_config();
+        assert_eq!(effective.interval, 10);
+        assert!(effective.nodelay);
+        assert_eq!(effective.resend, 2);
+        assert!(effective.nc);
+        assert_eq!(effective.min_rto, 30);
+        assert_eq!(effective.mtu, config.mtu);
+    }
+
+    #[tokio::test]
+    async fn effective_config() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        let effective = kcp.effective_config();
+        assert_eq!(effective.mtu, config.mtu);
+        assert_eq!(effective.mss, config.mtu - kcp::KCP_OVERHEAD);
+        assert_eq!((effective.snd_wnd, effective.rcv_wnd), config.wnd_size);
+        assert_eq!(effective.interval, config.nodelay.interval);
+        assert_eq!(effective.nodelay, config.nodelay.nodelay);
+
+        // Swapped at the next update
+        kcp.swap_nodelay_config(KcpNoDelayConfig::fastest());
+        assert_eq!(kcp.effective_config().interval, config.nodelay.interval);
+        kcp.update().unwrap();
+        let effective = kcp.effective_config();
+        assert_eq!(effective.interval, 10);
+        assert!(effective.nodelay);
+        assert_eq!(effective.resend, 2);
+        assert!(effective.nc);
+
+        // In effect right away
+        kcp.set_wndsize(64, 512);
+        kcp.set_mtu(1200).unwrap();
+        let effective = kcp.effective_config();
+        assert_eq!((effective.snd_wnd, effective.rcv_wnd), (64, 512));
+        assert_eq!(effective.mtu, 1200);
+        assert_eq!(effective.mss, 1200 - kcp::KCP_OVERHEAD);
+        // Untouched by them
+        assert_eq!(effective.interval, 10);
+    }
+
+    #[tokio::test]
+    async fn window_stalled() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            wnd_size: (1, 256),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+        assert!(!kcp.is_window_stalled());
+
+        // Nobody is going to ACK it
+        kcp.send(b"HELLO WORLD").await.unwrap();
+        assert!(kcp.is_window_stalled());
+
+        kcp.set_wndsize(4, 256);
+        assert_eq!(kcp.snd_wnd(), 4);
+        assert!(!kcp.is_window_stalled());
+
+        // Shrinking below the pending segments keeps them
+        kcp.send(b"HELLO WORLD").await.unwrap();
+        kcp.set_wndsize(1, 256);
+        kcp.update().unwrap();
+        assert_eq!(kcp.pending_messages(), 2);
+        assert!(kcp.is_window_stalled());
+    }
+
+    #[tokio::test]
+    async fn set_wndsize_shrink_pending() {
+        let _ = env_logger::try_init();
+
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr1 = udp1.local_addr().unwrap();
+        let addr2 = udp2.local_addr().unwrap();
+
+        let config = KcpConfig {
+            wnd_size: (4, 256),
+            ..first_flush_config()
+        };
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1.clone(), addr2, false).unwrap();
+        let mut kcp2 = KcpSocket::new(&config, 0xdeadbeef, udp2.clone(), addr1, false).unwrap();
+
+        kcp1.send(b"HELLO").await.unwrap();
+        kcp1.send(b"WORLD").await.unwrap();
+        kcp1.flush().unwrap();
+
+        // Fewer segments allowed than are waiting for ACKs, sending waits
+        kcp1.set_wndsize(1, 256);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(kcp1.poll_send(&mut cx, b"AGAIN").is_pending());
+
+        // Until they are acknowledged
+        deliver(&udp2, &mut kcp2).await;
+        kcp2.flush().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+        assert_eq!(kcp1.kcp.wait_snd(), 0);
+        assert!(matches!(kcp1.poll_send(&mut cx, b"AGAIN"), Poll::Ready(Ok(5))));
+        kcp1.flush().unwrap();
+        deliver(&udp2, &mut kcp2).await;
+
+        let mut buf = [0u8; 16];
+        for expected in [&b"HELLO"[..], b"WORLD", b"AGAIN"] {
+            let n = time::timeout(Duration::from_secs(1), kcp2.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn announce_window() {
+        let _ = env_logger::try_init();
+
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr1 = udp1.local_addr().unwrap();
+        let addr2 = udp2.local_addr().unwrap();
+
+        let config = KcpConfig {
+            wnd_size: (256, 128),
+            ..first_flush_config()
+        };
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1.clone(), addr2, false).unwrap();
+        let mut kcp2 = KcpSocket::new(&config, 0xdeadbeef, udp2.clone(), addr1, false).unwrap();
+
+        // Fill the receive window without reading
+        for _ in 0..128 {
+            kcp1.send(b"HELLO").await.unwrap();
+        }
+        kcp1.flush().unwrap();
+        deliver(&udp2, &mut kcp2).await;
+        kcp2.flush().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+        assert_eq!(kcp1.kcp.rmt_wnd(), 0);
+
+        // Reading reopens it, KCP's announcement of it is lost
+        let mut buf = [0u8; 16];
+        for _ in 0..128 {
+            kcp2.try_recv(&mut buf).unwrap();
+        }
+        kcp2.flush().unwrap();
+        while time::timeout(Duration::from_millis(100), udp1.recv(&mut buf))
+            .await
+            .is_ok()
+        {}
+        assert_eq!(kcp1.kcp.rmt_wnd(), 0);
+        assert!(kcp1.is_window_stalled());
+
+        // Long before the sender probes
+        kcp2.announce_window().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+        assert_eq!(kcp1.kcp.rmt_wnd(), 128);
+        assert!(!kcp1.is_window_stalled());
+    }
+
+    #[tokio::test]
+    async fn output_queue_backpressure() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            max_output_queue: Some(4096),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        // As if the socket kept returning WouldBlock
+        kcp.output_stats.delay_queued_bytes.store(4096, Ordering::Relaxed);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(kcp.poll_send(&mut cx, b"HELLO").is_pending());
+
+        kcp.output_stats.delay_queued_bytes.store(0, Ordering::Relaxed);
+        assert!(matches!(kcp.poll_send(&mut cx, b"HELLO"), Poll::Ready(Ok(5))));
+    }
+
+    #[tokio::test]
+    async fn flush_all() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        // Everything acknowledged, but still waiting to leave the socket
+        kcp.output_stats.delay_queued_bytes.store(4096, Ordering::Relaxed);
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(kcp.poll_wait_acked(&mut cx).is_ready());
+        assert!(kcp.poll_flush_all(&mut cx).is_pending());
+
+        kcp.output_stats.delay_queued_bytes.store(0, Ordering::Relaxed);
+        assert!(matches!(kcp.poll_flush_all(&mut cx), Poll::Ready(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_mtu() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            mtu: 1 << 30,
+            ..Default::default()
+        };
+        match KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false) {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            r => panic!("oversized MTU accepted, result: {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn set_mtu() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            mtu: 9000,
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(kcp.configured_mtu(), 9000);
+        assert_eq!(kcp.effective_mtu(), 9000);
+
+        kcp.set_mtu(1400).unwrap();
+        assert_eq!(kcp.configured_mtu(), 9000);
+        assert_eq!(kcp.effective_mtu(), 1400);
+
+        assert!(matches!(kcp.set_mtu(1 << 30), Err(KcpError::IoError(..))));
+        assert_eq!(kcp.effective_mtu(), 1400);
+    }
+
+    #[tokio::test]
+    async fn migrate_peer() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer2_addr = peer2.local_addr().unwrap();
+
+        let config = first_flush_config();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), peer1.local_addr().unwrap(), false).unwrap();
+
+        kcp.migrate_peer(peer2_addr);
+        assert_eq!(kcp.peer_addr(), peer2_addr);
+
+        kcp.send(b"HELLO").await.unwrap();
+        kcp.flush().unwrap();
+
+        let mut packet = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(1), peer2.recv(&mut packet))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&packet[kcp::KCP_OVERHEAD..n], b"HELLO");
+        assert!(peer1.try_recv(&mut packet).is_err());
+    }
+
+    #[tokio::test]
+    async fn missed_keepalives() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            keepalive_interval: Some(Duration::from_millis(10)),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+
+        time::sleep(Duration::from_millis(35)).await;
+        assert!(kcp.missed_keepalives() >= 3);
+
+        let packet = segment(CONV, super::KCP_CMD_ACK, 256, 0, 0, 0);
+        kcp.input(&packet).unwrap();
+        assert_eq!(kcp.missed_keepalives(), 0);
+    }
+
+    #[tokio::test]
+    async fn keepalive() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let s1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s1_addr = s1.local_addr().unwrap();
+        let s2_addr = s2.local_addr().unwrap();
+
+        let config = KcpConfig {
+            keepalive_interval: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let mut kcp1 = KcpSocket::new(&config, CONV, Arc::new(s1), s2_addr, false).unwrap();
+
+        time::sleep(Duration::from_millis(30)).await;
+        kcp1.update().unwrap();
+
+        let s2 = Arc::new(s2);
+        let mut packet = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(1), s2.recv(&mut packet))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, kcp::KCP_OVERHEAD);
+
+        // Absorbed by the peer
+        let mut kcp2 = KcpSocket::new(&KcpConfig::default(), CONV, s2, s1_addr, false).unwrap();
+        kcp2.input(&packet[..n]).unwrap();
+        assert!(matches!(kcp2.try_recv(&mut packet), Err(KcpError::RecvQueueEmpty)));
+        assert_eq!(kcp2.missed_keepalives(), 0);
+    }
+
+    #[tokio::test]
+    async fn mtu_probe() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s1_addr = s1.local_addr().unwrap();
+        let s2_addr = s2.local_addr().unwrap();
+
+        // Loopback carries up to 64KiB
+        let config = KcpConfig {
+            mtu_probe: Some(MtuProbeConfig {
+                max_mtu: 9000,
+                interval: Duration::from_millis(10),
+            }),
+            ..Default::default()
+        };
+        let mut kcp1 = KcpSocket::new(&config, CONV, s1.clone(), s2_addr, false).unwrap();
+        let mut kcp2 = KcpSocket::new(&KcpConfig::default(), CONV, s2.clone(), s1_addr, false).unwrap();
+        assert_eq!(kcp1.effective_mtu(), config.mtu);
+
+        let mut packet = [0u8; 65536];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while kcp1.effective_mtu() != 9000 && Instant::now() < deadline {
+            kcp1.update().unwrap();
+            kcp2.update().unwrap();
+            while let Ok(n) = s2.try_recv(&mut packet) {
+                kcp2.input(&packet[..n]).unwrap();
+            }
+            while let Ok(n) = s1.try_recv(&mut packet) {
+                kcp1.input(&packet[..n]).unwrap();
+            }
+            time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(kcp1.effective_mtu(), 9000);
+    }
+
+    #[tokio::test]
+    async fn mtu_probe_reply() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s1_addr = s1.local_addr().unwrap();
+        let s2_addr = s2.local_addr().unwrap();
+
+        let config = KcpConfig {
+            mtu_probe: Some(MtuProbeConfig {
+                max_mtu: 9000,
+                interval: Duration::from_millis(10),
+            }),
+            ..Default::default()
+        };
+        let mut kcp1 = KcpSocket::new(&config, CONV, s1.clone(), s2_addr, false).unwrap();
+        let mut kcp2 = KcpSocket::new(&KcpConfig::default(), CONV, s2.clone(), s1_addr, false).unwrap();
+
+        time::sleep(Duration::from_millis(20)).await;
+        kcp1.update().unwrap();
+        let (size, _) = kcp1.mtu_prober.as_ref().unwrap().in_flight.unwrap();
+
+        // KCP's WINS, e.g. answering a window probe, isn't a reply to the MTU probe
+        let packet = segment(CONV, super::KCP_CMD_WINS, 256, 0, 0, 0);
+        kcp1.input(&packet).unwrap();
+        assert_eq!(kcp1.effective_mtu(), config.mtu);
+
+        let mut packet = [0u8; 65536];
+        let n = s2.recv(&mut packet).await.unwrap();
+        kcp2.input(&packet[..n]).unwrap();
+        let n = s1.recv(&mut packet).await.unwrap();
+        kcp1.input(&packet[..n]).unwrap();
+        assert_eq!(kcp1.effective_mtu(), size);
+    }
+
+    #[test]
+    fn rtt_estimator() {
+        /// KCP's `update_ack` on its `rx_srtt`, `rx_rttval` and `rx_rto`
+        fn kcp_update_ack(state: &mut (u32, u32, u32), rtt: u32, interval: u32, minrto: u32) {
+            let (srtt, rttval, rto) = state;
+            if *srtt == 0 {
+                *srtt = rtt;
+                *rttval = rtt / 2;
+            } else {
+                let delta = rtt.abs_diff(*srtt);
+                *rttval = (3 * *rttval + delta) / 4;
+                *srtt = ((7 * *srtt + rtt) / 8).max(1);
+            }
+            *rto = (*srtt + interval.max(4 * *rttval)).clamp(minrto, 60000);
+        }
+
+        let normal = KcpNoDelayConfig::normal();
+        let fastest = KcpNoDelayConfig::fastest();
+
+        // KCP's initial RTO before any sample
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.rto(&normal), 200);
+
+        // The first sample is taken as it is, with half of it as variation
+        rtt.sample(80);
+        assert_eq!(rtt.srtt, Some(80));
+        assert_eq!(rtt.rttval, 40);
+        assert_eq!(rtt.rto(&normal), 80 + 160);
+
+        // Later ones are smoothed, following KCP step by step
+        for nodelay in [normal, fastest] {
+            let interval = nodelay.interval.clamp(10, 5000) as u32;
+            let mut rtt = RttEstimator::default();
+            let mut kcp = (0, 0, 0);
+            for sample in [80, 120, 60, 300, 5, 5, 5, 5, 5, 5, 5, 5, 100000] {
+                rtt.sample(sample);
+                kcp_update_ack(&mut kcp, sample, interval, RttEstimator::min_rto(&nodelay));
+                assert_eq!((rtt.srtt.unwrap(), rtt.rttval), (kcp.0, kcp.1));
+                assert_eq!(rtt.rto(&nodelay), kcp.2, "sample: {}, nodelay: {:?}", sample, nodelay);
+            }
+        }
+
+        // Clamped to the minimum RTO of the mode, and to KCP's maximum
+        let mut rtt = RttEstimator::default();
+        for _ in 0..32 {
+            rtt.sample(1);
+        }
+        assert_eq!(rtt.rto(&normal), 100);
+        assert_eq!(rtt.rto(&fastest), 30);
+        rtt.sample(1_000_000);
+        assert_eq!(rtt.rto(&normal), 60000);
+    }
+
+    #[tokio::test]
+    async fn last_rtt_sample() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(kcp.last_rtt_sample(), None);
+
+        let packet = segment(CONV, super::KCP_CMD_ACK, 256, now_millis().wrapping_sub(500), 0, 0);
+        kcp.input(&packet).unwrap();
+
+        let rtt = kcp.last_rtt_sample().unwrap();
+        assert!((500..1000).contains(&rtt));
+    }
+
+    #[tokio::test]
+    async fn pending_messages() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        // Nobody is going to ACK them
+        kcp.send(b"HELLO").await.unwrap();
+        kcp.send(&[0u8; 4000]).await.unwrap();
+        kcp.send(&[0u8; 1000]).await.unwrap();
+        kcp.update().unwrap();
+
+        assert_eq!(kcp.pending_messages(), 3);
+    }
+
+    #[test]
+    fn kcp_loopback_reliability() {
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let output1 = RecordOutput::default();
+        let mut kcp1 = Kcp::new(1, output1.clone());
+        config.apply_config(&mut kcp1).unwrap();
+
+        let mut kcp2 = Kcp::new(1, RecordOutput::default());
+        config.apply_config(&mut kcp2).unwrap();
+
+        // Each message fills one datagram
+        let messages: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 1300]).collect();
+        for message in &messages {
+            kcp1.send(message).unwrap();
+        }
+        kcp1.update(0).unwrap();
+
+        let mut datagrams = output1.take();
+        assert_eq!(datagrams.len(), messages.len());
+
+        // Lose the 2nd datagram
+        let lost = datagrams.remove(1);
+        for datagram in &datagrams {
+            kcp2.input(datagram).unwrap();
+        }
+
+        let mut buf = [0u8; 2048];
+        let n = kcp2.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &messages[0][..]);
+        assert!(matches!(kcp2.recv(&mut buf), Err(KcpError::RecvQueueEmpty)));
+
+        // Arrives late
+        kcp2.input(&lost).unwrap();
+        for message in &messages[1..] {
+            let n = kcp2.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..n], &message[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn last_update_flushed() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        kcp.update().unwrap();
+        assert!(!kcp.last_update_flushed());
+
+        kcp.send(b"HELLO WORLD").await.unwrap();
+        time::sleep(Duration::from_millis(config.nodelay.interval as u64 * 2)).await;
+        kcp.update().unwrap();
+        assert!(kcp.last_update_flushed());
+    }
+
+    #[tokio::test]
+    async fn health_score_retransmission() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        let weights = HealthScoreWeights {
+            loss: 1.0,
+            rtt: 0.0,
+            stall: 0.0,
+        };
+        assert_eq!(kcp.health_score(&weights), 1.0);
+
+        // Nobody is going to ACK it, KCP keeps resending
+        kcp.send(b"HELLO WORLD").await.unwrap();
+        for _ in 0..5 {
+            kcp.update().unwrap();
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(kcp.health_score(&weights) < 1.0);
+    }
+
+    #[test]
+    fn no_congestion_control() {
+        fn first_flush_segments(config: &KcpConfig) -> usize {
+            let output = RecordOutput::default();
+            let mut kcp = Kcp::new(1, output.clone());
+            config.apply_config(&mut kcp).unwrap();
+
+            let mss = kcp.mss();
+            for _ in 0..32 {
+                kcp.send(&vec![0u8; mss]).unwrap();
+            }
+            kcp.update(0).unwrap();
+
+            let mut segments = 0;
+            for datagram in output.take() {
+                super::walk_segments(&datagram, |header| {
+                    if header[4] == super::KCP_CMD_PUSH {
+                        segments += 1;
+                    }
+                });
+            }
+            segments
+        }
+
+        let config = KcpConfig {
+            wnd_size: (32, 32),
+            ..Default::default()
+        };
+        // KCP's congestion window is 0 until the end of its first flush, slow start sends nothing by it
+        assert_eq!(first_flush_segments(&config), 0);
+
+        let config = KcpConfig {
+            no_congestion_control: true,
+            ..config
+        };
+        assert_eq!(first_flush_segments(&config), 32);
+    }
+
+    #[tokio::test]
+    async fn peak_memory() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            max_session_memory: Some(8 * 1024),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(kcp.peak_memory(), 0);
+
+        // Nobody is going to ACK them
+        for _ in 0..10 {
+            kcp.send(&[0u8; 1000]).await.unwrap();
+        }
+        kcp.update().unwrap();
+
+        assert!(kcp.peak_memory() >= 10 * 1000);
+        assert!(kcp.is_memory_exceeded());
+    }
+
+    #[tokio::test]
+    async fn max_buffer_latency() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                interval: 1000,
+                ..KcpNoDelayConfig::normal()
+            },
+            max_buffer_latency: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        kcp.send(b"HELLO WORLD").await.unwrap();
+        let next = kcp.update().unwrap();
+        assert!(!kcp.last_update_flushed());
+        assert!(next <= std::time::Instant::now() + Duration::from_millis(20));
+
+        time::sleep(Duration::from_millis(25)).await;
+        kcp.update().unwrap();
+        assert!(kcp.last_update_flushed());
+    }
+
+    #[tokio::test]
+    async fn current_send_rate_limit() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            wnd_size: (8, 256),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(kcp.current_send_rate_limit(), None);
+
+        let ack = |wnd: u16| segment(CONV, super::KCP_CMD_ACK, wnd, now_millis().wrapping_sub(100), 0, 0);
+
+        // Peer advertises a receive window larger than the send window
+        kcp.input(&ack(64)).unwrap();
+        let (_, source) = kcp.current_send_rate_limit().unwrap();
+        assert_eq!(source, RateLimitSource::SendWindow);
+
+        // Peer advertises a receive window smaller than the send window
+        kcp.input(&ack(4)).unwrap();
+        let (rate, source) = kcp.current_send_rate_limit().unwrap();
+        assert_eq!(source, RateLimitSource::RemoteWindow);
+        assert!(rate > 0);
+
+        // Configured limits apply before the first RTT sample, the lowest is binding
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = KcpConfig {
+            max_pps: Some(50),
+            send_rate_limit: Some(100_000),
+            ..Default::default()
+        };
+        let kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(
+            kcp.current_send_rate_limit(),
+            Some((50 * config.mtu as u64, RateLimitSource::MaxPps))
+        );
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = KcpConfig {
+            send_rate_limit: Some(1000),
+            ..config
+        };
+        let kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(
+            kcp.current_send_rate_limit(),
+            Some((1000, RateLimitSource::SendRateLimit))
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicate_segments() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+
+        let push = |sn: u32| push_segment(CONV, 0, sn, b"HELLO");
+
+        kcp.input(&push(0)).unwrap();
+        kcp.input(&push(2)).unwrap();
+        assert_eq!(kcp.duplicate_segments(), 0);
+
+        // Replayed, delivered and received out of order
+        kcp.input(&push(0)).unwrap();
+        kcp.input(&push(2)).unwrap();
+        assert_eq!(kcp.duplicate_segments(), 2);
+
+        kcp.input(&push(1)).unwrap();
+        kcp.input(&push(1)).unwrap();
+        kcp.input(&push(2)).unwrap();
+        assert_eq!(kcp.duplicate_segments(), 4);
+    }
+
+    #[tokio::test]
+    async fn adaptive_fast_resend() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            adaptive_fast_resend: true,
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(kcp.fast_resend(), 2);
+
+        let push = |sn: u32, ts: u32| push_segment(CONV, ts, sn, b"HELLO");
+
+        // 0 is retransmitted after 1, 2 were sent, not reordered
+        kcp.input(&push(1, 100)).unwrap();
+        kcp.input(&push(2, 100)).unwrap();
+        kcp.input(&push(0, 200)).unwrap();
+        kcp.update().unwrap();
+        assert_eq!(kcp.max_reorder_distance(), 0);
+        assert_eq!(kcp.fast_resend(), 2);
+
+        // 3 is overtaken by 3 segments sent after it
+        for sn in 4..7 {
+            kcp.input(&push(sn, 300)).unwrap();
+        }
+        kcp.input(&push(3, 300)).unwrap();
+        kcp.update().unwrap();
+        assert_eq!(kcp.max_reorder_distance(), 3);
+        assert_eq!(kcp.fast_resend(), 4);
+
+        // Explicitly raised above the adaptive threshold
+        kcp.set_fast_resend(8);
+        assert_eq!(kcp.fast_resend(), 8);
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+
+        let stats = kcp.stats();
+        assert_eq!(stats.srtt, None);
+        assert_eq!(stats.segments_sent, 0);
+        assert_eq!(kcp.rtt(), Duration::ZERO);
+
+        // Nobody is going to ACK it, KCP keeps resending
+        kcp.send(b"HELLO WORLD").await.unwrap();
+        for _ in 0..5 {
+            kcp.update().unwrap();
+            time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let stats = kcp.stats();
+        assert_eq!(stats.wait_snd, 1);
+        assert!(stats.segments_sent >= 2);
+        assert_eq!(stats.segments_retransmitted, stats.segments_sent - 1);
+
+        let packet = segment(CONV, super::KCP_CMD_ACK, 256, now_millis().wrapping_sub(100), 0, 0);
+        kcp.input(&packet).unwrap();
+
+        let stats = kcp.stats();
+        let srtt = stats.srtt.unwrap().as_millis() as u64;
+        assert!(srtt >= 100);
+        // srtt + max(interval, 4 * rttval), rttval starts at half of the first sample
+        assert_eq!(stats.rto, Duration::from_millis(srtt + srtt / 2 * 4));
+        assert_eq!(kcp.rtt(), stats.srtt.unwrap());
+        assert_eq!(kcp.rto(), stats.rto);
+    }
+
+    #[tokio::test]
+    async fn segment_sizes() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = first_flush_config();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+        let mss = kcp.kcp.mss();
+
+        kcp.send(b"HELLO").await.unwrap();
+        kcp.send(&vec![0u8; mss]).await.unwrap();
+        kcp.send(&vec![0u8; mss * 2]).await.unwrap();
+        kcp.flush().unwrap();
+
+        let sizes = kcp.stats().segment_sizes;
+        assert_eq!(sizes[0], 1);
+        assert_eq!(sizes[super::segment_size_bucket(mss)], 3);
+        assert_eq!(sizes.iter().sum::<u64>(), 4);
+    }
+
+    #[tokio::test]
+    async fn since_last_ack() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        assert_eq!(kcp.since_last_ack(), Duration::ZERO);
+
+        kcp.send(b"HELLO").await.unwrap();
+        kcp.send(b"WORLD").await.unwrap();
+        kcp.flush().unwrap();
+
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(kcp.since_last_ack() >= Duration::from_millis(50));
+
+        // ACK of sn 0
+        let packet = segment(CONV, super::KCP_CMD_ACK, 256, 0, 0, 0);
+        kcp.input(&packet).unwrap();
+        assert!(kcp.since_last_ack() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn test_clock() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let clock = Arc::new(TestClock::new());
+        let config = KcpConfig {
+            clock: clock.clone(),
+            ..first_flush_config()
+        };
+        let interval = Duration::from_millis(config.nodelay.interval as u64);
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        kcp.send(b"HELLO").await.unwrap();
+        kcp.flush().unwrap();
+        assert_eq!(kcp.stats().segments_sent, 1);
+
+        // Time stands still, nothing is due before the RTO
+        let next = kcp.update().unwrap();
+        assert!(next >= clock.now() && next <= clock.now() + interval);
+        assert_eq!(kcp.stats().segments_retransmitted, 0);
+
+        clock.advance(Duration::from_millis(500));
+        let next = kcp.update().unwrap();
+        assert!(next >= clock.now() && next <= clock.now() + interval);
+        assert_eq!(kcp.stats().segments_retransmitted, 1);
+    }
+
+    #[tokio::test]
+    async fn dead_link() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let clock = Arc::new(TestClock::new());
+        let config = KcpConfig {
+            dead_link: Some(3),
+            clock: clock.clone(),
+            ..first_flush_config()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+
+        // Loss recovered before the limit
+        kcp.send(b"HELLO").await.unwrap();
+        kcp.flush().unwrap();
+        clock.advance(Duration::from_secs(10));
+        kcp.update().unwrap();
+        assert_eq!(kcp.stats().segments_retransmitted, 1);
+
+        // ACK of sn 0
+        let packet = segment(CONV, super::KCP_CMD_ACK, 256, 0, 0, 0);
+        kcp.input(&packet).unwrap();
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(10));
+            kcp.update().unwrap();
+        }
+        assert!(!kcp.is_dead());
+
+        // Never acknowledged
+        kcp.send(b"WORLD").await.unwrap();
+        for _ in 0..5 {
+            clock.advance(Duration::from_secs(60));
+            kcp.update().unwrap();
+        }
+        assert!(kcp.is_dead());
+
+        let mut buf = [0u8; 16];
+        let mut cx = Context::from_waker(noop_waker_ref());
+        match kcp.poll_recv(&mut cx, &mut buf) {
+            Poll::Ready(Err(KcpError::IoError(err))) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn is_handshaking() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let preset = KcpSocket::new(&config, CONV, udp.clone(), target_addr, false).unwrap();
+        assert!(!preset.is_handshaking());
+
+        let mut kcp = KcpSocket::new(&config, 0, udp, target_addr, false).unwrap();
+        assert!(kcp.is_handshaking());
+
+        // Server replied with the allocated conv
+        let packet = segment(CONV, super::KCP_CMD_WINS, 256, 0, 0, 0);
+        kcp.input(&packet).unwrap();
+        assert!(!kcp.is_handshaking());
+        assert_eq!(kcp.conv(), CONV);
+    }
+
+    #[tokio::test]
+    async fn max_pps() {
+        let _ = env_logger::try_init();
+
+        const MAX_PPS: u32 = 50;
+        const DATAGRAMS: usize = 30;
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = Arc::new(SpinMutex::new(receiver.local_addr().unwrap()));
+
+        let limiter = OutputLimiter::new(Arc::new(SystemClock), Some(MAX_PPS), None, None, None);
+        let mut output = UdpOutput::new(udp, target_addr, 0, None, Arc::default(), None, limiter);
+        let start = std::time::Instant::now();
+        for i in 0..DATAGRAMS {
+            output.write_all(&[i as u8]).unwrap();
+        }
+
+        let mut buf = [0u8; 16];
+        for i in 0..DATAGRAMS {
+            let n = time::timeout(Duration::from_secs(2), receiver.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            // Sent in order, no earlier than its slot
+            assert_eq!(&buf[..n], &[i as u8]);
+            assert!(start.elapsed() >= Duration::from_secs(1) / MAX_PPS * i as u32);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_rate_limit() {
+        let _ = env_logger::try_init();
+
+        const RATE: u64 = 10_000;
+        const DATAGRAMS: usize = 10;
+        const DATAGRAM_SIZE: usize = 500;
+
+        assert!(OutputLimiter::new(Arc::new(SystemClock), None, Some(0), None, None).is_none());
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = Arc::new(SpinMutex::new(receiver.local_addr().unwrap()));
+
+        let limiter = OutputLimiter::new(Arc::new(SystemClock), None, Some(RATE), None, None);
+        let mut output = UdpOutput::new(udp, target_addr, 0, None, Arc::default(), None, limiter);
+        let start = std::time::Instant::now();
+        for i in 0..DATAGRAMS {
+            output.write_all(&[i as u8; DATAGRAM_SIZE]).unwrap();
+        }
+
+        let mut buf = [0u8; 1024];
+        for i in 0..DATAGRAMS {
+            let n = time::timeout(Duration::from_secs(2), receiver.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buf[..n], &[i as u8; DATAGRAM_SIZE]);
+            // Bytes sent before it took their share of the second
+            let sent = (i * DATAGRAM_SIZE) as u64;
+            assert!(start.elapsed() >= Duration::from_millis(sent * 1000 / RATE));
+        }
+    }
+
+    #[test]
+    fn output_limiter_clock() {
+        let clock = Arc::new(TestClock::new());
+        let mut limiter = OutputLimiter::new(clock.clone(), Some(10), None, None, None).unwrap();
+
+        // Slots are spaced on the session's clock, not the system's
+        assert!(limiter.try_acquire(100));
+        assert!(!limiter.try_acquire(100));
+        assert_eq!(limiter.acquire(100), Duration::from_millis(100));
+
+        clock.advance(Duration::from_millis(200));
+        assert!(limiter.try_acquire(100));
+    }
+
+    #[tokio::test]
+    async fn pacing() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            pacing: true,
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        // Sent right away until the RTT is known
+        kcp.update().unwrap();
+        assert_eq!(kcp.pacing_rate(), None);
+
+        let ack = segment(CONV, super::KCP_CMD_ACK, 128, now_millis().wrapping_sub(100), 0, 0);
+        kcp.input(&ack).unwrap();
+        kcp.update().unwrap();
+
+        let (rate, _) = kcp.current_send_rate_limit().unwrap();
+        assert_eq!(kcp.pacing_rate(), Some(rate));
+
+        // A window of datagrams is spread over the RTT
+        let limiter = OutputLimiter::new(
+            Arc::new(SystemClock),
+            None,
+            None,
+            None,
+            Some(Arc::new(AtomicU64::new(10_000))),
+        )
+        .unwrap();
+        assert_eq!(limiter.spacing(1000), Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn cwnd_pacing() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            cwnd_pacing: true,
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+        let ack = segment(CONV, super::KCP_CMD_ACK, 128, now_millis().wrapping_sub(100), 0, 0);
+        kcp.input(&ack).unwrap();
+
+        // Slow start lets one segment out, the rest wait for the congestion window
+        let mss = kcp.kcp.mss();
+        for _ in 0..4 {
+            kcp.kcp.send(&vec![0u8; mss]).unwrap();
+        }
+        kcp.flush().unwrap();
+        kcp.flush().unwrap();
+        assert_eq!(kcp.cwnd_estimate, Some(1));
+        kcp.update().unwrap();
+        let srtt = kcp.rtt.srtt.unwrap() as u64;
+        assert_eq!(kcp.pacing_rate(), Some(mss as u64 * 1000 / srtt));
+    }
+
+    #[tokio::test]
+    async fn cwnd_pacing_spreads_window() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            wnd_size: (8, 128),
+            cwnd_pacing: true,
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(peer), target_addr, false).unwrap();
+        let ack = segment(CONV, super::KCP_CMD_ACK, 128, now_millis().wrapping_sub(100), 0, 0);
+        kcp.input(&ack).unwrap();
+
+        let mss = kcp.kcp.mss();
+        for _ in 0..16 {
+            kcp.kcp.send(&vec![0u8; mss]).unwrap();
+        }
+        kcp.flush().unwrap();
+        assert_eq!(kcp.cwnd_estimate, Some(8));
+
+        // A window of 8 datagrams is spread over the 100ms RTT rather than sent in a burst
+        let mut buf = vec![0u8; 2048];
+        let mut arrivals = Vec::new();
+        for _ in 0..8 {
+            time::timeout(Duration::from_secs(1), udp.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            arrivals.push(Instant::now());
+        }
+        let span = arrivals[7] - arrivals[0];
+        assert!(span >= Duration::from_millis(75), "{:?}", span);
+        assert!(span < Duration::from_millis(300), "{:?}", span);
+        for gap in arrivals.windows(2).map(|w| w[1] - w[0]) {
+            assert!(gap >= Duration::from_millis(5), "{:?}", gap);
+        }
     }
 
-    pub fn conv(&self) -> u32 {
-        self.kcp.conv()
+    #[tokio::test]
+    async fn control_segment_advertises_kcp_window() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            wnd_size: (32, 256),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), target_addr, false).unwrap();
+
+        let header = |packet: &[u8]| {
+            let wnd = u16::from_le_bytes([packet[6], packet[7]]);
+            let una = u32::from_le_bytes([packet[16], packet[17], packet[18], packet[19]]);
+            (wnd, una)
+        };
+        let packet = kcp.control_segment(super::KCP_CMD_WINS, super::KEEPALIVE_FRG, kcp::KCP_OVERHEAD);
+        assert_eq!(header(&packet), (256, 0));
+
+        // A message waits in the receive queue, KCP's ACK advertises the window left
+        kcp.input(&push_segment(CONV, 0, 0, b"HELLO")).unwrap();
+        kcp.flush().unwrap();
+        let packet = kcp.control_segment(super::KCP_CMD_WINS, super::KEEPALIVE_FRG, kcp::KCP_OVERHEAD);
+        assert_eq!(header(&packet), (255, 1));
     }
 
-    pub fn set_conv(&mut self, conv: u32) {
-        self.kcp.set_conv(conv);
+    #[tokio::test]
+    async fn owns_datagram() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        let mut packet = [0u8; kcp::KCP_OVERHEAD];
+        packet[..4].copy_from_slice(&0xdeadbeefu32.to_le_bytes());
+        assert!(kcp.owns_datagram(&packet));
+        assert!(!kcp.owns_datagram(&packet[..kcp::KCP_OVERHEAD - 1]));
+        assert!(!kcp.owns_datagram(&[]));
+
+        packet[..4].copy_from_slice(&0xcafebabeu32.to_le_bytes());
+        assert!(!kcp.owns_datagram(&packet));
     }
 
-    pub fn waiting_conv(&self) -> bool {
-        self.kcp.waiting_conv()
+    #[tokio::test]
+    async fn simultaneous_open_keeps_conv() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, 1, Arc::new(udp), target_addr, false).unwrap();
+        kcp.send(b"HELLO").await.unwrap();
+
+        // The peer's higher conv gives way, what it sent with that conv is still received
+        let packet = push_segment(2, 0, 0, b"WORLD");
+        assert!(kcp.resolve_simultaneous_open(&packet));
+        assert_eq!(kcp.conv(), 1);
+        assert!(kcp.owns_datagram(&packet));
+        kcp.input(&packet).unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = kcp.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"WORLD");
     }
 
-    pub fn peek_size(&self) -> KcpResult<usize> {
-        self.kcp.peeksize()
+    #[tokio::test]
+    async fn simultaneous_open_switches_on_ack() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp = KcpSocket::new(&config, 2, Arc::new(udp), target_addr, false).unwrap();
+        kcp.send(b"HELLO").await.unwrap();
+
+        // The peer kept its lower conv and only acknowledged ours so far
+        let packet = segment(1, super::KCP_CMD_ACK, 256, now_millis(), 0, 1);
+        assert!(kcp.resolve_simultaneous_open(&packet));
+        assert_eq!(kcp.conv(), 1);
     }
 
-    pub fn last_update_time(&self) -> Instant {
-        self.last_update
+    #[tokio::test]
+    async fn fin() {
+        let _ = env_logger::try_init();
+
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr1 = udp1.local_addr().unwrap();
+        let addr2 = udp2.local_addr().unwrap();
+
+        let config = first_flush_config();
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1.clone(), addr2, false).unwrap();
+        let mut kcp2 = KcpSocket::new(&config, 0xdeadbeef, udp2.clone(), addr1, false).unwrap();
+
+        kcp1.send(b"HELLO").await.unwrap();
+        kcp1.flush().unwrap();
+        kcp1.close();
+        deliver(&udp2, &mut kcp2).await;
+
+        // FIN waits for the ACK of everything sent
+        assert!(!kcp2.peer_closed);
+        kcp2.flush().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+        kcp1.update().unwrap();
+        deliver(&udp2, &mut kcp2).await;
+
+        // Data received before the FIN is still readable, EOF after that
+        assert!(!kcp2.is_peer_closed());
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut buf = [0u8; 1024];
+        match kcp2.poll_recv(&mut cx, &mut buf) {
+            Poll::Ready(Ok(n)) => assert_eq!(&buf[..n], b"HELLO"),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(kcp2.is_peer_closed());
+        assert!(matches!(kcp2.poll_recv(&mut cx, &mut buf), Poll::Ready(Ok(0))));
+        assert!(kcp2.send(b"WORLD").await.is_err());
     }
 
-    pub fn need_flush(&self) -> bool {
-        (self.kcp.wait_snd() >= self.kcp.snd_wnd() as usize || self.kcp.wait_snd() >= self.kcp.rmt_wnd() as usize)
-            && !self.kcp.waiting_conv()
+    #[tokio::test]
+    async fn recv_bytes() {
+        let _ = env_logger::try_init();
+
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr1 = udp1.local_addr().unwrap();
+        let addr2 = udp2.local_addr().unwrap();
+
+        let config = first_flush_config();
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1.clone(), addr2, false).unwrap();
+        let mut kcp2 = KcpSocket::new(&config, 0xdeadbeef, udp2.clone(), addr1, false).unwrap();
+        let large = vec![0x42u8; kcp1.kcp.mss() * 2 + 1];
+
+        kcp1.send(b"HELLO").await.unwrap();
+        kcp1.send(&large).await.unwrap();
+        kcp1.flush().unwrap();
+        kcp1.close();
+        deliver(&udp2, &mut kcp2).await;
+
+        // FIN follows the ACK of everything sent
+        kcp2.flush().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+        kcp1.update().unwrap();
+        deliver(&udp2, &mut kcp2).await;
+
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            time::timeout(timeout, kcp2.recv_bytes()).await.unwrap().unwrap(),
+            &b"HELLO"[..]
+        );
+        assert_eq!(time::timeout(timeout, kcp2.recv_bytes()).await.unwrap().unwrap(), large);
+        // EOF after the peer's FIN
+        assert!(time::timeout(timeout, kcp2.recv_bytes())
+            .await
+            .unwrap()
+            .unwrap()
+            .is_empty());
     }
-}
 
-#[cfg(test)]
-mod test {
+    #[tokio::test]
+    async fn udp_send_retry() {
+        let _ = env_logger::try_init();
 
-    use kcp::Error as KcpError;
-    use log::trace;
-    use std::sync::Arc;
-    use tokio::{
-        net::UdpSocket,
-        sync::Mutex,
-        time::{self, Instant},
-    };
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr2 = udp2.local_addr().unwrap();
 
-    use super::KcpSocket;
-    use crate::config::KcpConfig;
+        let config = first_flush_config();
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1.clone(), addr2, false).unwrap();
+
+        // The first send and two delayed retries fail transiently, the third retry goes through
+        kcp1.output_stats
+            .fail_sends
+            .lock()
+            .extend([io::ErrorKind::Interrupted; 3]);
+        kcp1.send(b"HELLO").await.unwrap();
+        kcp1.flush().unwrap();
+
+        let mut packet = [0u8; 2048];
+        let n = time::timeout(Duration::from_secs(1), udp2.recv(&mut packet))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(n > kcp::KCP_OVERHEAD);
+        assert_eq!(kcp1.udp_send_retry_count(), 3);
+
+        // An IPv6 target can't be reached from an IPv4 socket, that is no reason to retry
+        let mut kcp3 = KcpSocket::new(&config, 0xdeadbeef, udp1, "[::1]:9".parse().unwrap(), false).unwrap();
+        kcp3.send(b"HELLO").await.unwrap();
+        assert!(kcp3.flush().is_err());
+        assert_eq!(kcp3.udp_send_retry_count(), 0);
+    }
 
     #[tokio::test]
-    async fn kcp_echo() {
+    async fn udp_send_would_block() {
         let _ = env_logger::try_init();
 
-        static CONV: u32 = 0xdeadbeef;
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr2 = udp2.local_addr().unwrap();
 
-        // s1 connects s2
-        let s1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
-        let s2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = first_flush_config();
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1, addr2, false).unwrap();
 
-        let s1_addr = s1.local_addr().unwrap();
-        let s2_addr = s2.local_addr().unwrap();
+        // Loopback frees the send buffer as soon as a datagram is sent, a full one can't be provoked by a small
+        // SO_SNDBUF, it is injected instead
+        kcp1.output_stats.fail_sends.lock().push_back(io::ErrorKind::WouldBlock);
+        kcp1.send(b"HELLO").await.unwrap();
+        kcp1.flush().unwrap();
+        assert_eq!(kcp1.udp_send_would_block_count(), 1);
 
-        let s1 = Arc::new(s1);
-        let s2 = Arc::new(s2);
+        // Sent by the delayed sender
+        let mut packet = [0u8; 2048];
+        let n = time::timeout(Duration::from_secs(1), udp2.recv(&mut packet))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(n > kcp::KCP_OVERHEAD);
+        kcp1.send(b"HELLO").await.unwrap();
+        kcp1.flush().unwrap();
+        assert_eq!(kcp1.udp_send_would_block_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn udp_send_queue_delay() {
+        let _ = env_logger::try_init();
+
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr2 = udp2.local_addr().unwrap();
+
+        // Datagrams after the first wait 50ms for their slots
+        let config = KcpConfig {
+            max_pps: Some(20),
+            ..first_flush_config()
+        };
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1, addr2, false).unwrap();
+        assert_eq!(kcp1.udp_send_queue_delay(), (Duration::ZERO, Duration::ZERO));
+
+        // A datagram each
+        let message = vec![0x42u8; kcp1.kcp.mss()];
+        for _ in 0..3 {
+            kcp1.send(&message).await.unwrap();
+        }
+        kcp1.flush().unwrap();
+
+        let mut packet = [0u8; 2048];
+        for _ in 0..3 {
+            time::timeout(Duration::from_secs(1), udp2.recv(&mut packet))
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        // Recorded right after the last one is sent
+        time::sleep(Duration::from_millis(10)).await;
+
+        let (avg, max) = kcp1.udp_send_queue_delay();
+        assert!(avg > Duration::from_millis(25), "{:?}", avg);
+        assert!(max >= avg);
+        assert!(max > Duration::from_millis(75), "{:?}", max);
+    }
+
+    #[tokio::test]
+    async fn set_log_level() {
+        let _ = env_logger::try_init();
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            log_level: LevelFilter::Off,
+            ..Default::default()
+        };
+        let mut quiet = KcpSocket::new(&config, 0xdeadbeef, udp.clone(), target_addr, false).unwrap();
+        let mut traced = KcpSocket::new(&config, 0xdeadbeef, udp, target_addr, false).unwrap();
+        traced.set_log_level(LevelFilter::Trace);
+
+        // Every empty receive is traced, for the session allowing it only
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut buf = [0u8; 16];
+        let traces = SESSION_TRACES.with(Cell::get);
+        assert!(quiet.poll_recv(&mut cx, &mut buf).is_pending());
+        assert_eq!(SESSION_TRACES.with(Cell::get), traces);
+        assert!(traced.poll_recv(&mut cx, &mut buf).is_pending());
+        assert_eq!(SESSION_TRACES.with(Cell::get), traces + 1);
+    }
+
+    /// Input every datagram arriving at `udp` within 100ms into `kcp`
+    async fn deliver(udp: &UdpSocket, kcp: &mut KcpSocket) {
+        let mut packet = [0u8; 2048];
+        while let Ok(n) = time::timeout(Duration::from_millis(100), udp.recv(&mut packet)).await {
+            kcp.input(&packet[..n.unwrap()]).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_write() {
+        let _ = env_logger::try_init();
+
+        let udp1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let udp2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let addr1 = udp1.local_addr().unwrap();
+        let addr2 = udp2.local_addr().unwrap();
+
+        let config = first_flush_config();
+        let mut kcp1 = KcpSocket::new(&config, 0xdeadbeef, udp1.clone(), addr2, false).unwrap();
+        let mut kcp2 = KcpSocket::new(&config, 0xdeadbeef, udp2.clone(), addr1, false).unwrap();
+
+        kcp1.send(b"HELLO").await.unwrap();
+        kcp1.shutdown_write().unwrap();
+        assert!(kcp1.send(b"HELLO").await.is_err());
+        deliver(&udp2, &mut kcp2).await;
+
+        // SHUTDOWN follows the ACK of everything sent
+        kcp2.flush().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+        kcp1.update().unwrap();
+        deliver(&udp2, &mut kcp2).await;
+
+        let mut buf = [0u8; 1024];
+        assert_eq!(kcp2.recv(&mut buf).await.unwrap(), 5);
+        assert_eq!(kcp2.recv(&mut buf).await.unwrap(), 0);
+
+        // The other direction is still open
+        kcp2.send(b"WORLD").await.unwrap();
+        kcp2.shutdown_write().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+        kcp1.flush().unwrap();
+        deliver(&udp2, &mut kcp2).await;
+        kcp2.update().unwrap();
+        deliver(&udp1, &mut kcp1).await;
+
+        let n = kcp1.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"WORLD");
+        assert_eq!(kcp1.recv(&mut buf).await.unwrap(), 0);
+        assert!(kcp1.is_shut_down());
+    }
+
+    #[tokio::test]
+    async fn freeze_idle_timer() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
 
         let config = KcpConfig::default();
-        let kcp1 = KcpSocket::new(&config, 0, s1.clone(), s2_addr, true).unwrap();
-        let kcp2 = KcpSocket::new(&config, CONV, s2.clone(), s1_addr, true).unwrap();
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
 
-        let kcp1 = Arc::new(Mutex::new(kcp1));
-        let kcp2 = Arc::new(Mutex::new(kcp2));
+        time::sleep(Duration::from_millis(20)).await;
+        kcp.freeze_idle_timer();
+        let frozen = kcp.idle_time();
+        assert!(frozen >= Duration::from_millis(20));
 
-        let kcp1_task = {
-            let kcp1 = kcp1.clone();
-            tokio::spawn(async move {
-                loop {
-                    let mut kcp = kcp1.lock().await;
-                    let next = kcp.update().expect("update");
-                    trace!("kcp1 next tick {:?}", next);
-                    time::sleep_until(Instant::from_std(next)).await;
-                }
-            })
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(kcp.idle_time(), frozen);
+
+        // Resumed from where it was frozen
+        kcp.unfreeze_idle_timer();
+        let idle = kcp.idle_time();
+        assert!(idle >= frozen && idle < frozen + Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn packet_transform() {
+        let _ = env_logger::try_init();
+
+        let xor = Arc::new(XorTransform(0x5a));
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = KcpConfig {
+            packet_transform: Some(xor.clone()),
+            ..first_flush_config()
         };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), peer.local_addr().unwrap(), false).unwrap();
 
-        let kcp2_task = {
-            let kcp2 = kcp2.clone();
-            tokio::spawn(async move {
-                loop {
-                    let mut kcp = kcp2.lock().await;
-                    let next = kcp.update().expect("update");
-                    trace!("kcp2 next tick {:?}", next);
-                    time::sleep_until(Instant::from_std(next)).await;
-                }
-            })
+        kcp.send(b"HELLO").await.unwrap();
+        kcp.flush().unwrap();
+
+        let mut packet = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(1), peer.recv(&mut packet))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(kcp::get_conv(&packet[..n]), 0xdeadbeef);
+
+        let n = xor.decode(&mut packet[..n]);
+        assert_eq!(kcp::get_conv(&packet[..n]), 0xdeadbeef);
+        assert_eq!(&packet[kcp::KCP_OVERHEAD..n], b"HELLO");
+    }
+
+    #[tokio::test]
+    async fn write_timeout() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            write_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
         };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+        kcp.send(b"HELLO").await.unwrap();
 
-        const SEND_BUFFER: &[u8] = b"HELLO WORLD";
+        // As if the socket stayed blocked, nothing leaves the delayed queue
+        kcp.output_stats.delay_queued_bytes.store(4096, Ordering::Relaxed);
+        kcp.update().unwrap();
+        assert!(!kcp.is_write_timed_out());
 
-        {
-            let n = kcp1.lock().await.send(SEND_BUFFER).await.unwrap();
-            assert_eq!(n, SEND_BUFFER.len());
+        time::sleep(Duration::from_millis(60)).await;
+        kcp.update().unwrap();
+        assert!(kcp.is_write_timed_out());
+        match kcp.send(b"HELLO").await {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            r => panic!("unexpected {:?}", r),
         }
+    }
 
-        let echo_task = tokio::spawn(async move {
-            let mut buf = [0u8; 1024];
+    #[tokio::test]
+    async fn send_queue_order() {
+        let _ = env_logger::try_init();
 
-            loop {
-                let n = s2.recv(&mut buf).await.unwrap();
+        static CONV: u32 = 0xdeadbeef;
 
-                let packet = &mut buf[..n];
+        for (order, expected) in [
+            (
+                SendQueueOrder::Lifo { drop_superseded: false },
+                &[&b"STATE0"[..], b"STATE3", b"STATE2", b"STATE1"][..],
+            ),
+            (
+                SendQueueOrder::Lifo { drop_superseded: true },
+                &[&b"STATE0"[..], b"STATE3"][..],
+            ),
+        ] {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
 
-                let conv = kcp::get_conv(packet);
-                if conv == 0 {
-                    kcp::set_conv(packet, CONV);
-                }
+            let config = KcpConfig {
+                wnd_size: (1, 256),
+                send_queue_order: order,
+                ..first_flush_config()
+            };
+            let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), peer.local_addr().unwrap(), false).unwrap();
 
-                let mut kcp2 = kcp2.lock().await;
-                kcp2.input(packet).unwrap();
+            // The first takes the whole window, the rest wait for it
+            for i in 0..4 {
+                kcp.send_with_class(format!("STATE{}", i).as_bytes(), SendClass::Interactive)
+                    .unwrap();
+            }
 
-                match kcp2.try_recv(&mut buf) {
-                    Ok(n) => {
-                        let received = &buf[..n];
-                        kcp2.send(received).await.unwrap();
-                    }
-                    Err(KcpError::RecvQueueEmpty) => {
-                        continue;
-                    }
-                    Err(err) => {
-                        panic!("kcp.recv error: {:?}", err);
-                    }
-                }
+            let mut sent = Vec::new();
+            let mut packet = [0u8; 1024];
+            for sn in 0..expected.len() as u32 {
+                kcp.flush().unwrap();
+                let n = time::timeout(Duration::from_secs(1), peer.recv(&mut packet))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                sent.push(packet[kcp::KCP_OVERHEAD..n].to_vec());
+
+                let ack = segment(CONV, super::KCP_CMD_ACK, 128, now_millis(), sn, sn + 1);
+                kcp.input(&ack).unwrap();
             }
-        });
 
-        {
-            let mut buf = [0u8; 1024];
+            assert_eq!(sent, expected, "{:?}", order);
+            assert_eq!(kcp.class_queued_bytes, 0);
+        }
+    }
 
-            loop {
-                let n = s1.recv(&mut buf).await.unwrap();
+    #[tokio::test]
+    async fn send_with_class() {
+        let _ = env_logger::try_init();
 
-                let packet = &buf[..n];
+        static CONV: u32 = 0xdeadbeef;
 
-                let mut kcp1 = kcp1.lock().await;
-                kcp1.input(packet).unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
 
-                match kcp1.try_recv(&mut buf) {
-                    Ok(n) => {
-                        let received = &buf[..n];
-                        assert_eq!(received, SEND_BUFFER);
-                        break;
-                    }
-                    Err(KcpError::RecvQueueEmpty) => {
-                        continue;
-                    }
-                    Err(err) => {
-                        panic!("kcp.recv error: {:?}", err);
-                    }
-                }
+        let config = KcpConfig {
+            wnd_size: (1, 256),
+            ..first_flush_config()
+        };
+        let mut kcp = KcpSocket::new(&config, CONV, Arc::new(udp), peer.local_addr().unwrap(), false).unwrap();
+
+        // Takes the whole window, the rest have to wait
+        kcp.send_with_class(b"BULK0", SendClass::Bulk).unwrap();
+        kcp.send_with_class(b"BULK1", SendClass::Bulk).unwrap();
+        kcp.send_with_class(b"INTERACTIVE", SendClass::Interactive).unwrap();
+        kcp.send_with_class(b"CONTROL", SendClass::Control).unwrap();
+        assert!(kcp.memory_usage() >= b"BULK1INTERACTIVECONTROL".len());
+
+        let mut sent = Vec::new();
+        let mut packet = [0u8; 1024];
+        for sn in 0u32..4 {
+            kcp.flush().unwrap();
+            let n = time::timeout(Duration::from_secs(1), peer.recv(&mut packet))
+                .await
+                .unwrap()
+                .unwrap();
+            sent.push(packet[kcp::KCP_OVERHEAD..n].to_vec());
+
+            let ack = segment(CONV, super::KCP_CMD_ACK, 128, now_millis(), sn, sn + 1);
+            kcp.input(&ack).unwrap();
+        }
+
+        assert_eq!(
+            sent,
+            [&b"BULK0"[..], b"CONTROL", b"INTERACTIVE", b"BULK1"]
+                .iter()
+                .map(|m| m.to_vec())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn try_send_window_full() {
+        let _ = env_logger::try_init();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = udp.local_addr().unwrap();
+
+        let config = KcpConfig {
+            wnd_size: (1, 256),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(udp), target_addr, false).unwrap();
+
+        // Nobody is going to ACK it
+        kcp.try_send(b"HELLO", false).unwrap();
+        match kcp.try_send(b"HELLO", false) {
+            Err(err) => {
+                assert!(SendWindowFull::is(&err));
+                assert!(matches!(err, KcpError::IoError(ref err) if err.kind() == io::ErrorKind::WouldBlock));
             }
+            r => panic!("unexpected {:?}", r),
         }
 
-        echo_task.abort();
-        kcp1_task.abort();
-        kcp2_task.abort();
+        assert_eq!(kcp.try_send(b"HELLO", true).unwrap(), 5);
+        let would_block = KcpError::IoError(io::ErrorKind::WouldBlock.into());
+        assert!(!SendWindowFull::is(&would_block));
     }
 }
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+/// Upper bounds of the segment size buckets, the last bucket takes everything larger
+const SEGMENT_SIZE_BOUNDS: [usize; 5] = [64, 128, 256, 512, 1024];
+
+/// Number of buckets in `KcpStats::segment_sizes`
+pub(crate) const SEGMENT_SIZE_BUCKETS: usize = SEGMENT_SIZE_BOUNDS.len() + 1;
+
+/// Bucket of a segment with `len` bytes of payload
+pub(crate) fn segment_size_bucket(len: usize) -> usize {
+    SEGMENT_SIZE_BOUNDS
+        .iter()
+        .position(|&bound| len <= bound)
+        .unwrap_or(SEGMENT_SIZE_BOUNDS.len())
+}
+
+/// Snapshot of a session's transmission state, see `KcpStream::stats`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KcpStats {
+    /// Smoothed RTT, `None` before the first ACK
+    ///
+    /// Estimated outside KCP from the ACKs received, it approximates KCP's own and may drift from it.
+    pub srtt: Option<Duration>,
+    /// Retransmission timeout derived from `srtt` with KCP's formula, an approximation of the one KCP uses
+    pub rto: Duration,
+    /// Segments waiting to be sent or acknowledged
+    pub wait_snd: usize,
+    /// Data segments sent, including retransmissions
+    pub segments_sent: u64,
+    /// Data segments received, including duplicates
+    pub segments_received: u64,
+    /// Data segments retransmitted
+    pub segments_retransmitted: u64,
+    /// Payload sizes of data segments sent, including retransmissions
+    ///
+    /// Bucketed by `<= 64`, `<= 128`, `<= 256`, `<= 512`, `<= 1024` and larger bytes.
+    pub segment_sizes: [u64; SEGMENT_SIZE_BUCKETS],
+}
+
+/// Why a session was closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Closed locally, by dropping or closing its stream
+    Closed,
+    /// Peer sent a FIN
+    PeerClosed,
+    /// Server session idle for longer than `KcpConfig::session_expire`
+    Inactive,
+    /// Buffers exceeded `KcpConfig::max_session_memory`
+    MemoryExceeded,
+    /// Packets waited for the UDP socket longer than `KcpConfig::write_timeout`
+    WriteTimeout,
+    /// Nothing was received from the peer within `KcpConfig::connect_timeout`
+    ConnectTimeout,
+    /// A segment was sent `KcpConfig::dead_link` times without being acknowledged
+    DeadLink,
+}
+
+/// Totals of a closed session, see `KcpStream::set_on_close`
+#[derive(Debug, Clone, Copy)]
+pub struct SessionSummary {
+    pub conv: u32,
+    /// Time since the session was created
+    pub duration: Duration,
+    /// Bytes accepted by `send`
+    pub bytes_sent: u64,
+    /// Bytes returned by `recv`, or pushed to `on_data`
+    pub bytes_received: u64,
+    /// Data segments retransmitted
+    pub segments_retransmitted: u64,
+    /// Largest RTT sampled, `None` if nothing was acknowledged
+    pub max_rtt: Option<Duration>,
+    pub reason: CloseReason,
+}
+
+/// Peers of a listener, see `KcpListener::peer_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Distinct peer addresses that ever had a session
+    pub total_seen: usize,
+    /// Peers with a live session
+    pub active: usize,
+}
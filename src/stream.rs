@@ -5,17 +5,94 @@ use std::{
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
+use bytes::Bytes;
 use futures::{future, ready};
 use kcp::{Error as KcpError, KcpResult};
-use log::trace;
+use log::{trace, LevelFilter};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::UdpSocket,
+    time,
 };
 
-use crate::{config::KcpConfig, session::KcpSession, skcp::KcpSocket};
+use crate::{
+    config::{EffectiveConfig, HealthScoreWeights, KcpConfig, KcpNoDelayConfig, NatPunchConfig},
+    priority::SendClass,
+    rate::RateLimitSource,
+    session::KcpSession,
+    skcp::KcpSocket,
+    stats::{KcpStats, SessionSummary},
+};
+
+/// Send empty datagrams to `addr` for opening NAT mappings on the way
+pub(crate) async fn punch_nat(udp: &UdpSocket, nat_punch: &NatPunchConfig, addr: SocketAddr) -> io::Result<()> {
+    for i in 0..nat_punch.count {
+        if i > 0 {
+            time::sleep(nat_punch.interval).await;
+        }
+        udp.send_to(&[], addr).await?;
+    }
+    trace!("[CLIENT] punched NAT to {} with {} datagrams", addr, nat_punch.count);
+    Ok(())
+}
+
+/// Length of the logical stream id prepended by `KcpStream::send_on_stream`
+const STREAM_ID_LEN: usize = 2;
+
+/// Progress of `KcpStream::poll_send_large`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendLargeState {
+    sent: usize,
+}
+
+impl SendLargeState {
+    /// Bytes of the message that have been fed into KCP
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+}
+
+/// Handle sending on a `KcpStream` without waiting, usable from callbacks like `KcpStream::set_on_data`
+#[derive(Clone)]
+pub struct KcpSender {
+    session: Arc<KcpSession>,
+}
+
+impl Debug for KcpSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KcpSender").finish()
+    }
+}
+
+impl KcpSender {
+    /// `send` data in `buf` if the send window allows
+    ///
+    /// Fails with `SendWindowFull` (a `WouldBlock`) while the window is stalled,
+    /// the caller may drop the data or try again later.
+    pub fn try_send(&self, buf: &[u8]) -> KcpResult<usize> {
+        self.send(buf, false)
+    }
+
+    /// `send` data in `buf` regardless of the send window
+    ///
+    /// Data beyond the window is queued in KCP, which grows without bound if the peer can't keep up.
+    pub fn send_ignoring_window(&self, buf: &[u8]) -> KcpResult<usize> {
+        self.send(buf, true)
+    }
+
+    fn send(&self, buf: &[u8], ignore_window: bool) -> KcpResult<usize> {
+        if self.session.is_closed() {
+            return Err(io::Error::from(ErrorKind::BrokenPipe).into());
+        }
+
+        let n = self.session.kcp_socket().lock().try_send(buf, ignore_window)?;
+        self.session.notify();
+        Ok(n)
+    }
+}
 
 pub struct KcpStream {
     session: Arc<KcpSession>,
@@ -54,6 +131,10 @@ impl KcpStream {
 
     /// Create a `KcpStream` with an existed `UdpSocket` connecting to `addr`
     pub async fn connect_with_socket(config: &KcpConfig, udp: UdpSocket, addr: SocketAddr) -> KcpResult<KcpStream> {
+        if let Some(ref nat_punch) = config.nat_punch {
+            punch_nat(&udp, nat_punch, addr).await?;
+        }
+
         let udp = Arc::new(udp);
         let conv = rand::random();
         let socket = KcpSocket::new(config, conv, udp, addr, config.stream)?;
@@ -74,6 +155,11 @@ impl KcpStream {
 
     /// `send` data in `buf`
     pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        // Session is closing (closed by user or expired), data sent now may never be delivered.
+        if self.session.is_closed() {
+            return Err(io::Error::from(ErrorKind::BrokenPipe).into()).into();
+        }
+
         // Mutex doesn't have poll_lock, spinning on it.
         let mut kcp = self.session.kcp_socket().lock();
         let result = ready!(kcp.poll_send(cx, buf));
@@ -86,6 +172,85 @@ impl KcpStream {
         future::poll_fn(|cx| self.poll_send(cx, buf)).await
     }
 
+    /// `send` a large `data` incrementally, feeding at most what the send window allows at a time
+    ///
+    /// Progress is kept in `state`, which must be passed again with the same `data` until it is ready.
+    /// In message mode every chunk is delivered as a separate message, use stream mode to get a byte stream.
+    pub fn poll_send_large(
+        &mut self,
+        cx: &mut Context<'_>,
+        data: &[u8],
+        state: &mut SendLargeState,
+    ) -> Poll<KcpResult<()>> {
+        while state.sent < data.len() {
+            let available = self.session.kcp_socket().lock().send_window_available();
+            let end = data.len().min(state.sent + available);
+            let n = ready!(self.poll_send(cx, &data[state.sent..end]))?;
+            state.sent += n;
+        }
+        Ok(()).into()
+    }
+
+    /// `send` a large `data` incrementally, see `poll_send_large`
+    pub async fn send_large(&mut self, data: &[u8]) -> KcpResult<()> {
+        let mut state = SendLargeState::default();
+        future::poll_fn(|cx| self.poll_send_large(cx, data, &mut state)).await
+    }
+
+    /// `send` all data in `buf`, resolves after the peer has acknowledged everything sent so far
+    ///
+    /// Fails if the session is closed or the link is dead before being acknowledged.
+    pub async fn send_confirmed(&mut self, mut buf: &[u8]) -> KcpResult<()> {
+        while !buf.is_empty() {
+            let n = self.send(buf).await?;
+            buf = &buf[n..];
+        }
+
+        self.commit().await
+    }
+
+    /// Shut down writing, the peer's `recv` returns EOF after receiving everything sent so far
+    ///
+    /// `recv` goes on until the peer shuts down its writing or closes, the session is closed after both peers
+    /// have shut down writing.
+    pub fn shutdown_write(&self) -> KcpResult<()> {
+        self.session.kcp_socket().lock().shutdown_write()?;
+        self.session.notify();
+        Ok(())
+    }
+
+    /// Flush all pending data, resolves after the peer has acknowledged all of them
+    ///
+    /// Fails if the session is closed or the link is dead before being acknowledged.
+    pub async fn commit(&mut self) -> KcpResult<()> {
+        self.session.kcp_socket().lock().flush()?;
+        self.session.notify();
+
+        future::poll_fn(|cx| {
+            // Mutex doesn't have poll_lock, spinning on it.
+            let mut kcp = self.session.kcp_socket().lock();
+            kcp.poll_wait_acked(cx)
+        })
+        .await
+    }
+
+    /// Flush all pending data, resolves after the peer has acknowledged all of them and every datagram delayed by
+    /// the socket has been sent
+    ///
+    /// Unlike `commit`, nothing is left in the session's output queue once it resolves, so it can be dropped right
+    /// away. Fails if the session is closed, the link is dead or the output stays blocked beyond `write_timeout`.
+    pub async fn flush_all(&mut self) -> KcpResult<()> {
+        self.session.kcp_socket().lock().flush()?;
+        self.session.notify();
+
+        future::poll_fn(|cx| {
+            // Mutex doesn't have poll_lock, spinning on it.
+            let mut kcp = self.session.kcp_socket().lock();
+            kcp.poll_flush_all(cx)
+        })
+        .await
+    }
+
     /// `recv` data into `buf`
     pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
         loop {
@@ -142,6 +307,395 @@ impl KcpStream {
         future::poll_fn(|cx| self.poll_recv(cx, buf)).await
     }
 
+    /// `recv` the next message into a buffer allocated with its exact size, to be forwarded without copying it again
+    ///
+    /// An empty `Bytes` is EOF, as `recv` returning `0`. Data left by a `recv` into a smaller buffer is returned first.
+    pub async fn recv_bytes(&mut self) -> KcpResult<Bytes> {
+        if self.recv_buffer_pos < self.recv_buffer_cap {
+            let data = Bytes::copy_from_slice(&self.recv_buffer[self.recv_buffer_pos..self.recv_buffer_cap]);
+            self.recv_buffer_pos = self.recv_buffer_cap;
+            return Ok(data);
+        }
+
+        future::poll_fn(|cx| self.poll_recv_message(cx)).await
+    }
+
+    /// `send` data in `buf` as one message of the logical stream `id`
+    ///
+    /// Logical streams are multiplexed on this session and share its congestion control,
+    /// every message carries a 2 bytes stream id. Requires message mode.
+    pub async fn send_on_stream(&mut self, id: u16, buf: &[u8]) -> KcpResult<usize> {
+        if self.session.kcp_socket().lock().is_stream() {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "logical streams require message mode").into());
+        }
+
+        let mut message = Vec::with_capacity(STREAM_ID_LEN + buf.len());
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(buf);
+
+        let n = self.send(&message).await?;
+        Ok(n.saturating_sub(STREAM_ID_LEN))
+    }
+
+    /// `recv` one message sent by `send_on_stream`, with its logical stream id
+    ///
+    /// Shouldn't be mixed with `recv` on the same `KcpStream`.
+    pub async fn recv_with_stream(&mut self) -> KcpResult<(u16, Bytes)> {
+        let mut message = future::poll_fn(|cx| self.poll_recv_message(cx)).await?;
+
+        match message.len() {
+            0 => Err(io::Error::from(ErrorKind::UnexpectedEof).into()),
+            n if n < STREAM_ID_LEN => Err(io::Error::new(ErrorKind::InvalidData, "missing logical stream id").into()),
+            _ => {
+                let id = message.split_to(STREAM_ID_LEN);
+                Ok((u16::from_be_bytes([id[0], id[1]]), message))
+            }
+        }
+    }
+
+    /// `recv` one whole message into a newly allocated buffer
+    fn poll_recv_message(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Bytes>> {
+        // Mutex doesn't have poll_lock, spinning on it.
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.poll_recv_bytes(cx)
+    }
+
+    /// Number of complete messages received
+    ///
+    /// Only counted in message mode. Always `0` if `KcpConfig::stream` is enabled.
+    pub fn messages_received(&self) -> u64 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.messages_received()
+    }
+
+    /// Number of failed UDP sends that have been retried
+    pub fn udp_send_retry_count(&self) -> u64 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.udp_send_retry_count()
+    }
+
+    /// Number of input datagrams dropped for carrying too many ACKs
+    pub fn ack_flood_dropped(&self) -> u64 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.ack_flood_dropped()
+    }
+
+    /// Number of data segments received from the peer that had been received before
+    ///
+    /// Some are expected from retransmissions, an abnormally high rate indicates a replay attack or a broken path.
+    pub fn duplicate_segments(&self) -> u64 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.duplicate_segments()
+    }
+
+    /// Replace the congestion parameters, applied atomically on the next tick
+    ///
+    /// Returns the replaced parameters.
+    pub fn swap_nodelay_config(&self, nodelay: KcpNoDelayConfig) -> KcpNoDelayConfig {
+        let previous = self.session.kcp_socket().lock().swap_nodelay_config(nodelay);
+        self.session.notify();
+        previous
+    }
+
+    /// Set the number of skipping ACKs that triggers a fast retransmit, `0` disables fast retransmit
+    ///
+    /// Raising it avoids spurious retransmits on paths reordering heavily.
+    /// It is replaced by the next `swap_nodelay_config`.
+    pub fn set_fast_resend(&self, threshold: u32) {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.set_fast_resend(threshold);
+    }
+
+    /// Fast resend threshold in effect, possibly raised by `KcpConfig::adaptive_fast_resend`
+    pub fn fast_resend(&self) -> u32 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.fast_resend()
+    }
+
+    /// Change the window sizes in segments while the session is live
+    ///
+    /// Segments already queued are kept. Shrinking the send window below the number of pending segments
+    /// is not rejected, `send` waits until enough of them are acknowledged. KCP raises a receive window
+    /// below 128 to 128.
+    pub fn set_wndsize(&self, snd_wnd: u16, rcv_wnd: u16) {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.set_wndsize(snd_wnd, rcv_wnd);
+    }
+
+    /// Tell the peer the current receive window right away, e.g. after reading reopened a full window
+    ///
+    /// KCP tells the peer once when the window reopens, if that is lost the peer waits for its next window probe.
+    pub fn announce_window(&self) -> KcpResult<()> {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.announce_window()
+    }
+
+    /// Change the MTU while the session is live, at most 65507 bytes
+    ///
+    /// Only data sent afterwards is segmented by it.
+    pub fn set_mtu(&self, mtu: usize) -> KcpResult<()> {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.set_mtu(mtu)
+    }
+
+    /// MTU from `KcpConfig::mtu`
+    pub fn configured_mtu(&self) -> usize {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.configured_mtu()
+    }
+
+    /// MTU in effect, smaller than `configured_mtu` if it has been reduced for the path
+    pub fn effective_mtu(&self) -> usize {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.effective_mtu()
+    }
+
+    /// Send window size in segments
+    pub fn snd_wnd(&self) -> u16 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.snd_wnd()
+    }
+
+    /// Receive window size in segments
+    pub fn rcv_wnd(&self) -> u16 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.rcv_wnd()
+    }
+
+    /// Check if `send` is blocked because pending data filled up the send window
+    pub fn is_window_stalled(&self) -> bool {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.is_window_stalled()
+    }
+
+    /// Set level of per-packet logs of this session
+    pub fn set_log_level(&self, level: LevelFilter) {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.set_log_level(level);
+    }
+
+    /// Number of `KcpConfig::keepalive_interval` passed without receiving anything from the peer
+    pub fn missed_keepalives(&self) -> u32 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.missed_keepalives()
+    }
+
+    /// Most recent RTT sample, taken from the last ACK received
+    ///
+    /// Unlike the smoothed RTT, it reflects momentary spikes. `None` before the first ACK.
+    pub fn last_rtt_sample(&self) -> Option<Duration> {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.last_rtt_sample().map(|rtt| Duration::from_millis(rtt as u64))
+    }
+
+    /// Number of UDP sends delayed because the socket's send buffer was full
+    ///
+    /// A growing value means the socket or the network can't keep up.
+    pub fn udp_send_would_block_count(&self) -> u64 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.udp_send_would_block_count()
+    }
+
+    /// Average and maximum time delayed UDP packets waited before being sent
+    ///
+    /// High values mean the socket is the bottleneck rather than the network.
+    pub fn udp_send_queue_delay(&self) -> (Duration, Duration) {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.udp_send_queue_delay()
+    }
+
+    /// Number of messages sent but not yet fully acknowledged by the peer
+    ///
+    /// Only counted in message mode. Always `0` if `KcpConfig::stream` is enabled.
+    pub fn pending_messages(&self) -> usize {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.pending_messages()
+    }
+
+    /// Check if the last KCP tick emitted any datagram
+    pub fn last_update_flushed(&self) -> bool {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.last_update_flushed()
+    }
+
+    /// Health of the session in `[0, 1]`, combining loss, RTT trend and window stalls
+    ///
+    /// Meant for adaptive clients, e.g. lowering the bitrate when it drops.
+    pub fn health_score(&self, weights: &HealthScoreWeights) -> f32 {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.health_score(weights)
+    }
+
+    /// Effective send rate limit in bytes per second and the mechanism imposing it
+    ///
+    /// Tells which limit is binding when throughput hits a ceiling. `None` if nothing limits it yet, the windows
+    /// do from the first RTT sample.
+    pub fn current_send_rate_limit(&self) -> Option<(u64, RateLimitSource)> {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.current_send_rate_limit()
+    }
+
+    /// Parameters in effect on the session, reflecting changes made at runtime
+    pub fn effective_config(&self) -> EffectiveConfig {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.effective_config()
+    }
+
+    /// Smoothed RTT, approximated from ACKs outside KCP which doesn't expose its own
+    ///
+    /// `Duration::ZERO` before the first ACK arrived.
+    pub fn rtt(&self) -> Duration {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.rtt()
+    }
+
+    /// Retransmission timeout approximated from `rtt` with KCP's formula, KCP's initial 200ms before the first ACK
+    pub fn rto(&self) -> Duration {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.rto()
+    }
+
+    /// Snapshot of the session's transmission state, cheap enough to be polled periodically
+    pub fn stats(&self) -> KcpStats {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.stats()
+    }
+
+    /// Metrics of this session in Prometheus text exposition format, labeled by conv and peer
+    ///
+    /// Use `tokio_kcp::prometheus_metrics` to render several streams for one `/metrics` endpoint.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus_metrics(&self) -> String {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.prometheus_metrics()
+    }
+
+    /// Time since the peer last acknowledged pending data, `0` if nothing is pending
+    ///
+    /// Unlike the time since the last input, it grows while the peer is responsive but not acknowledging
+    /// anything, telling a stalled send side before the link is considered dead.
+    pub fn since_last_ack(&self) -> Duration {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.since_last_ack()
+    }
+
+    /// Peak of the estimated bytes buffered by this session
+    ///
+    /// Covers data waiting to be sent or acknowledged, the next message ready to be received,
+    /// and packets waiting in the output queue.
+    pub fn peak_memory(&self) -> usize {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.peak_memory()
+    }
+
+    /// Push every message received to `on_data`, instead of waiting for `recv`
+    ///
+    /// It is called from the session's I/O task. Messages pushed to it are not returned by `recv`,
+    /// and messages received before it is set are left for `recv`.
+    ///
+    /// The session isn't locked while it is called, so sending through a `KcpSender` from it is safe.
+    /// Calling `set_on_data` or `clear_on_data` from it deadlocks.
+    pub fn set_on_data<F>(&self, on_data: F)
+    where
+        F: FnMut(Bytes) + Send + 'static,
+    {
+        self.session.set_on_data(Some(Box::new(on_data)));
+    }
+
+    /// Send a message of priority `class` without waiting
+    ///
+    /// While the send window is full, messages are queued and handed to KCP by class as it opens, in
+    /// `KcpConfig::send_queue_order` within a class, ahead of those waiting in `send`. The queue isn't bounded by the window, see `KcpStream::peak_memory`.
+    pub fn send_with_class(&self, buf: &[u8], class: SendClass) -> KcpResult<usize> {
+        let n = self.session.kcp_socket().lock().send_with_class(buf, class)?;
+        self.session.notify();
+        Ok(n)
+    }
+
+    /// Get a handle sending without waiting, usable from callbacks
+    pub fn sender(&self) -> KcpSender {
+        KcpSender {
+            session: self.session.clone(),
+        }
+    }
+
+    /// Stop pushing messages to the callback set by `set_on_data`
+    pub fn clear_on_data(&self) {
+        self.session.set_on_data(None);
+    }
+
+    /// Stop the idle timer expiring the session, e.g. while the application is paused
+    ///
+    /// Unlike keep-alives nothing is sent, the peer may still expire its side.
+    pub fn freeze_idle_timer(&self) {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.freeze_idle_timer();
+    }
+
+    /// Resume the idle timer from where it was frozen
+    pub fn unfreeze_idle_timer(&self) {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.unfreeze_idle_timer();
+    }
+
+    /// Call `on_close` with the session's totals once it is closed
+    ///
+    /// It is called exactly once, from the session's update task, even if the stream has been dropped.
+    /// Never called if the session was already closed when it was set.
+    pub fn set_on_close<F>(&self, on_close: F)
+    where
+        F: FnOnce(SessionSummary) + Send + 'static,
+    {
+        self.session.set_on_close(Some(Box::new(on_close)));
+    }
+
+    /// Send everything to `peer_addr` from now on, e.g. after the peer roamed to another network
+    ///
+    /// Packets already waiting to be sent still go to the previous address. Sessions accepted by a `KcpListener`
+    /// are still dispatched datagrams from the address they were accepted from.
+    pub fn migrate_peer(&self, peer_addr: SocketAddr) {
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.migrate_peer(peer_addr);
+    }
+
+    /// Address of the peer
+    pub fn peer_addr(&self) -> SocketAddr {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.peer_addr()
+    }
+
+    /// Current limit of bytes sent per second, the lower of `KcpConfig::send_rate_limit` and the bandwidth pool's share
+    pub fn send_rate_limit(&self) -> Option<u64> {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.send_rate_limit()
+    }
+
+    /// Current pacing rate in bytes per second, `None` unless `KcpConfig::pacing` or before the first RTT sample
+    pub fn pacing_rate(&self) -> Option<u64> {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.pacing_rate()
+    }
+
+    /// Weight of this session's share of `KcpConfig::bandwidth_pool`, default is 1
+    pub fn set_bandwidth_weight(&self, weight: u32) {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.set_bandwidth_weight(weight);
+    }
+
+    /// Check if the session is still waiting for the server to allocate its conv, e.g. for showing "connecting"
+    pub fn is_handshaking(&self) -> bool {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.is_handshaking()
+    }
+
+    /// Check if a segment was sent `KcpConfig::dead_link` times without being acknowledged
+    ///
+    /// The session is closed right after, reconnect instead of waiting for it to expire.
+    pub fn is_dead(&self) -> bool {
+        let kcp = self.session.kcp_socket().lock();
+        kcp.is_dead()
+    }
+
     /// Get the `KcpSession` for this `KcpStream`
     pub fn session(&self) -> &KcpSession {
         &self.session
@@ -184,7 +738,7 @@ impl AsyncWrite for KcpStream {
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Ok(()).into()
+        self.shutdown_write().map_err(Into::into).into()
     }
 }
 
@@ -0,0 +1,156 @@
+use std::{io, pin::Pin};
+
+use futures::future;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::stream::KcpStream;
+
+/// Bytes read from the source at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Read at most `buf.len()` bytes from `reader`, `0` at EOF
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read_buf = ReadBuf::new(buf);
+    future::poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut read_buf)).await?;
+    Ok(read_buf.filled().len())
+}
+
+/// Send everything read from `reader` through `stream`, skipping its first `start_offset` bytes
+///
+/// Resuming a transfer broken with its session, `start_offset` is how much the peer has received, known by the
+/// application's own protocol. Chunks are fed as the send window allows, `progress` is called with the offset
+/// of `reader` reached after each of them. Resolves with the offset at EOF, once the peer has acknowledged all.
+///
+/// In message mode every chunk is delivered as a separate message, use stream mode to get a byte stream.
+pub async fn kcp_send_stream<R, F>(
+    stream: &mut KcpStream,
+    mut reader: R,
+    start_offset: u64,
+    mut progress: F,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin,
+    F: FnMut(u64),
+{
+    let mut buf = vec![0u8; CHUNK_SIZE];
+
+    let mut offset = 0;
+    while offset < start_offset {
+        let len = buf.len().min((start_offset - offset) as usize);
+        match read_chunk(&mut reader, &mut buf[..len]).await? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "start offset beyond the source",
+                ))
+            }
+            n => offset += n as u64,
+        }
+    }
+
+    loop {
+        let n = read_chunk(&mut reader, &mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        stream.send_large(&buf[..n]).await?;
+        offset += n as u64;
+        progress(offset);
+    }
+
+    stream.commit().await?;
+    Ok(offset)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::{AsyncRead, ReadBuf};
+
+    use super::kcp_send_stream;
+    use crate::{config::KcpConfig, listener::KcpListener, stream::KcpStream};
+
+    /// Source failing after `limit` bytes, as a transfer interrupted midway
+    struct InterruptedReader<'a> {
+        data: &'a [u8],
+        limit: usize,
+    }
+
+    impl AsyncRead for InterruptedReader<'_> {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            if self.limit == 0 {
+                return Err(io::Error::from(io::ErrorKind::ConnectionReset)).into();
+            }
+            let n = self.limit.min(self.data.len()).min(buf.remaining());
+            buf.put_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            self.limit -= n;
+            Ok(()).into()
+        }
+    }
+
+    /// Receive `len` bytes from the next accepted stream
+    async fn accept_and_recv(listener: &mut KcpListener, len: usize) -> Vec<u8> {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while received.len() < len {
+            let n = stream.recv(&mut buffer).await.unwrap();
+            assert_ne!(n, 0);
+            received.extend_from_slice(&buffer[..n]);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn resume_transfer() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            stream: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let data = (0..300_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        const INTERRUPTED_AT: usize = 100_000;
+
+        let server = tokio::spawn(async move {
+            let mut received = accept_and_recv(&mut listener, INTERRUPTED_AT).await;
+            received.extend(accept_and_recv(&mut listener, 300_000 - received.len()).await);
+            received
+        });
+
+        // First session breaks midway
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let reader = InterruptedReader {
+            data: &data,
+            limit: INTERRUPTED_AT,
+        };
+        let mut reached = 0;
+        let err = kcp_send_stream(&mut stream, reader, 0, |offset| reached = offset)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(reached, INTERRUPTED_AT as u64);
+        // Everything sent is delivered before closing, the peer's received bytes are where to resume
+        stream.commit().await.unwrap();
+        drop(stream);
+
+        // Resumed by a new session from what the peer received
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let offset = kcp_send_stream(&mut stream, &data[..], INTERRUPTED_AT as u64, |_| {})
+            .await
+            .unwrap();
+        assert_eq!(offset, data.len() as u64);
+
+        assert_eq!(server.await.unwrap(), data);
+    }
+}
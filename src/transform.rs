@@ -0,0 +1,26 @@
+use std::fmt::Debug;
+
+/// Transformation applied to every datagram on the wire, e.g. obfuscation or encryption
+///
+/// `encode` is applied to datagrams right before they are sent, `decode` to datagrams right after they are
+/// received, before anything reads their KCP headers. Both ends of a session must use the same transform.
+///
+/// Encoded datagrams larger than the original ones must still fit the path MTU, lower `KcpConfig::mtu` for it.
+pub trait PacketTransform: Debug + Send + Sync {
+    /// Encode a datagram in place
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decode a datagram in place, returning the length of the decoded datagram at the start of `buf`
+    ///
+    /// Return `0` for datagrams failing to decode, they are dropped.
+    fn decode(&self, buf: &mut [u8]) -> usize;
+}
+
+/// Decode a received datagram with `transform` if there is one, returning its decoded length
+pub(crate) fn decode_packet(transform: Option<&dyn PacketTransform>, buf: &mut [u8]) -> usize {
+    match transform {
+        // Empty datagrams are NAT punching, never encoded
+        Some(transform) if !buf.is_empty() => transform.decode(buf),
+        _ => buf.len(),
+    }
+}